@@ -1,10 +1,10 @@
-﻿use clap::Parser;
+﻿use clap::{Parser, Subcommand};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone, Default)]
 #[command(version, about, long_about = None)]
 pub struct CliArgs {
     /// A direct path to the config file
-    #[arg(long, short)]
+    #[arg(long, short, global = true)]
     pub config_file: Option<String>,
 
     /// The host to bind the web server to
@@ -14,4 +14,41 @@ pub struct CliArgs {
     /// The port to run the webserver on
     #[arg(long, short)]
     pub port: Option<u16>,
+
+    /// Runs a tool instead of the exporter
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Validates the config file and exits with a non-zero status if it has problems
+    CheckConfig,
+
+    /// Runs the targets once, prints what was parsed from their output and exits
+    #[command(alias = "test-target")]
+    RunOnce {
+        /// Only runs the target with this name
+        #[arg(long, short)]
+        target: Option<String>,
+    },
+
+    /// Applies the RegEx of a target to sample output and prints what is parsed from it
+    TestRegex {
+        /// The name of the target whose RegEx is used
+        #[arg(long, short)]
+        target: String,
+
+        /// The file that contains the sample output, reads stdin if it is not set or '-'
+        #[arg(long, short)]
+        input: Option<String>,
+
+        /// Uses this RegEx instead of the one of the target
+        #[arg(long)]
+        regex: Option<String>,
+
+        /// Uses this group instead of the 'regex_named_group' of the target
+        #[arg(long)]
+        regex_named_group: Option<String>,
+    },
 }
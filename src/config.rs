@@ -1,12 +1,21 @@
 use crate::cli::CliArgs;
-use crate::config::schema::Schema;
-use log::{debug, error, info, warn};
+use crate::config::error::{ConfigError, ConfigErrors};
+use crate::config::schema::{MetricType, OutputParser, Schema, StaleAfter};
+use crate::config::source_map::SourceMap;
+use crate::json_parser;
+use crate::prometheus::TARGET_METRIC_NAMES;
+use crate::scheduler::Schedule;
+use crate::transform;
+use log::{debug, info, warn};
 use regex::bytes::Regex;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+pub mod error;
 pub mod schema;
+mod source_map;
 
 const CONFIG_FILE_DIR_NAME: &str = "prometheus_periodic_commands";
 
@@ -15,26 +24,35 @@ const CONFIG_FILE_DIR_NAME: &str = "prometheus_periodic_commands";
 /// ### Cli Args
 /// If the config path is specified in the cli arguments, it will have priority over
 /// the OS specific paths.
+pub fn find_cfg_path(cli_args: &CliArgs) -> Result<String, ConfigError> {
+    match &cli_args.config_file {
+        None => explore_config_file_paths().ok_or_else(|| {
+            ConfigError::new("Could not find any valid config file in common paths")
+        }),
+        Some(config_file) => Ok(config_file.clone()),
+    }
+}
+
+/// Reads and validates the config file at the path.
+///
+/// ### Cli Args
 /// Any cli overrides (host, port) will be applied to the config read from the file before it
 /// is returned by this function.
-pub fn read_cfg(cli_args: &CliArgs) -> Result<schema::Schema, String> {
-    let found_config_path = {
-        if cli_args.config_file == None {
-            explore_config_file_paths()
-        } else {
-            cli_args.config_file.clone().unwrap()
-        }
-    };
-
-    if !Path::new(&found_config_path).exists() {
-        error!("The specified config path does not point to a file.");
-        return Err("Config file could be found".to_string());
+///
+/// ## Errors
+/// All problems that were found in the config file
+pub fn read_cfg(found_config_path: &str, cli_args: &CliArgs) -> Result<Schema, ConfigErrors> {
+    if !Path::new(&found_config_path).is_file() {
+        return Err(ConfigError::new(format!(
+            "The config path '{found_config_path}' does not point to a file"
+        ))
+        .into());
     }
 
     info!("Loading config file: '{found_config_path}'");
 
     let mut file = match File::open(found_config_path) {
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(ConfigError::new(e.to_string()).into()),
         Ok(x) => x,
     };
 
@@ -42,7 +60,7 @@ pub fn read_cfg(cli_args: &CliArgs) -> Result<schema::Schema, String> {
     let mut file_buf: Vec<u8> = vec![];
     match file.read_to_end(&mut file_buf) {
         Ok(size) => debug!("Read {size} bytes from the config file"),
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(ConfigError::new(e.to_string()).into()),
     };
 
     if file_buf.starts_with(&[0xEF, 0xBB, 0xBF]) {
@@ -51,15 +69,32 @@ pub fn read_cfg(cli_args: &CliArgs) -> Result<schema::Schema, String> {
         warn!("Stripped the BOM of the config file");
     }
 
-    let file_str = String::from_utf8(file_buf).expect("Could not read the file contents");
+    let file_str = match String::from_utf8(file_buf) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(ConfigError::new(format!("The config file is not valid UTF-8: {e}")).into())
+        }
+    };
 
-    let mut read_config: schema::Schema = match serde_yml::from_str(&file_str) {
+    parse_cfg(&file_str, cli_args)
+}
+
+/// Parses and validates the text of a config file
+pub fn parse_cfg(file_str: &str, cli_args: &CliArgs) -> Result<Schema, ConfigErrors> {
+    let mut read_config: Schema = match serde_yml::from_str(file_str) {
         Ok(x) => x,
-        Err(err) => return Err(err.to_string()),
+        Err(err) => {
+            let mut error = ConfigError::new(err.to_string());
+            error.location = err.location().map(|location| error::Location {
+                line: location.line(),
+                column: location.column(),
+            });
+            return Err(error.into());
+        }
     };
 
     let config_host = read_config.host.clone();
-    let config_port = read_config.port.clone();
+    let config_port = read_config.port;
 
     read_config.host = match cli_args.host.clone() {
         None => config_host,
@@ -77,15 +112,56 @@ pub fn read_cfg(cli_args: &CliArgs) -> Result<schema::Schema, String> {
         }
     };
 
-    validate_config_labels(&read_config);
-    validate_config_regex(&read_config);
-    validate_config_command_labels(&read_config);
+    let mut errors = validate_config(&read_config);
+    if errors.is_empty() {
+        return Ok(read_config);
+    }
+
+    let source_map = SourceMap::new(file_str);
+    for error in errors.iter_mut() {
+        error.location = match (error.target_index, error.field) {
+            (Some(index), field) => source_map.locate_target(index, field),
+            (None, Some(field)) => source_map.locate_key(field),
+            (None, None) => None,
+        };
+    }
+    // The errors without a location are reported last
+    errors.sort_by_key(|error| match error.location {
+        Some(location) => (false, location.line),
+        None => (true, 0),
+    });
+
+    Err(ConfigErrors(errors))
+}
+
+/// Runs all validations and returns every problem that was found
+pub fn validate_config(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    errors.extend(validate_config_labels(config));
+    errors.extend(validate_config_target_names(config));
+    errors.extend(validate_config_regex(config));
+    errors.extend(validate_config_command_labels(config));
+    errors.extend(validate_config_label_placeholders(config));
+    errors.extend(validate_config_value_groups(config));
+    errors.extend(validate_config_json(config));
+    errors.extend(validate_config_value_map(config));
+    errors.extend(validate_config_transform(config));
+    errors.extend(validate_config_metric_type(config));
+    errors.extend(validate_config_prometheus_parser(config));
+    errors.extend(validate_config_duration_buckets(config));
+    errors.extend(validate_config_stale_after(config));
+    errors.extend(validate_config_schedule(config));
+    errors.extend(validate_config_concurrency(config));
+    errors.extend(validate_config_commands(config));
+    errors.extend(validate_config_command_environment(config));
 
-    Ok(read_config)
+    // Report the problems in the order of the file
+    errors.sort_by_key(|error| error.target_index.map_or(0, |index| index + 1));
+    errors
 }
 
 /// Test for common file paths to find a config file
-fn explore_config_file_paths() -> String {
+fn explore_config_file_paths() -> Option<String> {
     let config_file_names = [String::from("config.yaml")];
 
     #[cfg(target_os = "linux")]
@@ -103,9 +179,13 @@ fn explore_config_file_paths() -> String {
 
     // check current dir
     for possible_config_path in os_specific_config_dirs.clone() {
-        let expanded_path = shellexpand::full(&possible_config_path).expect(&format!(
-            "Could not expand the config path: {possible_config_path}"
-        ));
+        let expanded_path = match shellexpand::full(&possible_config_path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Could not expand the config path {possible_config_path}: {e}");
+                continue;
+            }
+        };
 
         let expanded_path_str = expanded_path.to_string();
         debug!("Checking dir: {expanded_path_str}");
@@ -120,94 +200,633 @@ fn explore_config_file_paths() -> String {
         // Check if a file with a valid config file name exists in the directory
         for possible_config_file_name in config_file_names.clone() {
             let file_path = config_dir_path.join(possible_config_file_name);
-            debug!("Checking for file {}", file_path.to_str().unwrap());
+            debug!("Checking for file {}", file_path.display());
             if file_path.exists() {
-                return file_path
-                    .as_os_str()
-                    .to_str()
-                    .expect("Could not convert to str")
-                    .to_string();
+                return Some(file_path.to_string_lossy().to_string());
             }
         }
     }
 
-    panic!("Could not find any valid config file in common paths.");
+    None
 }
 
-/// ## Panics
+/// ## Errors
 /// If a name in the config file contains any illegal character according to
 /// https://prometheus.io/docs/concepts/data_model/
-fn validate_config_labels(config: &Schema) {
+fn validate_config_labels(config: &Schema) -> Vec<ConfigError> {
     let re = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
-    for target in &config.targets {
-        if !re.is_match((&target.name).as_ref()) {
-            panic!(
-                "Found illegal character in command target name.
-            '{}' did not match the RegEx specified by the Prometheus specifications.
-            More information can be found here: https://prometheus.io/docs/concepts/data_model/",
-                target.name
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if !re.is_match(target.name.as_ref()) {
+            errors.push(
+                ConfigError::for_target(
+                    index,
+                    target,
+                    "The name contains illegal characters, see https://prometheus.io/docs/concepts/data_model/",
+                )
+                .at_field("name"),
             );
         }
     }
+
+    errors
 }
 
-/// ## Panics
+/// ## Errors
+/// If two targets have the same name
+fn validate_config_target_names(config: &Schema) -> Vec<ConfigError> {
+    let mut names = HashSet::new();
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if !names.insert(&target.name) {
+            errors.push(
+                ConfigError::for_target(index, target, "Another target has the same name")
+                    .at_field("name"),
+            );
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
 /// If any regex in the config is not able to be built.
-fn validate_config_regex(config: &Schema) {
-    for target in &config.targets {
-        match Regex::new(&*target.regex) {
-            Ok(re) => re,
-            Err(err) => panic!(
-                "Could not build RegEx for target '{}'
-             Error: {}",
-                target.name,
-                err.to_string()
-            ),
-        };
+fn validate_config_regex(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if target.parser != OutputParser::Regex {
+            continue;
+        }
+
+        if let Err(err) = Regex::new(&target.regex) {
+            errors.push(
+                ConfigError::for_target(index, target, format!("Could not build the RegEx: {err}"))
+                    .at_field("regex"),
+            );
+            continue;
+        }
+
+        if target.regex_named_group.is_empty() {
+            if target.value_groups.is_empty() {
+                errors.push(
+                    ConfigError::for_target(
+                        index,
+                        target,
+                        "The target needs a 'regex_named_group' or 'value_groups'",
+                    )
+                    .at_field("regex_named_group"),
+                );
+            }
+            continue;
+        }
 
         let group_signature = format!("(?<{}>", target.regex_named_group);
         if !&target.regex.contains(&group_signature) {
-            panic!(
-                "The RegEx of target '{}' does not contain a group called '{}'",
-                target.name, target.regex_named_group
+            errors.push(
+                ConfigError::for_target(
+                    index,
+                    target,
+                    format!(
+                        "The RegEx does not contain a group called '{}'",
+                        target.regex_named_group
+                    ),
+                )
+                .at_field("regex_named_group"),
             );
         }
     }
+
+    errors
 }
 
-/// ## Panics
+/// ## Errors
 /// If a label name for a target command does not match the Prometheus specification
 /// https://prometheus.io/docs/concepts/data_model/
-fn validate_config_command_labels(config: &Schema) {
+fn validate_config_command_labels(config: &Schema) -> Vec<ConfigError> {
     let re = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+    let mut errors = vec![];
 
-    for target in &config.targets {
-        for command in target.commands.clone() {
-            for (label_name, _) in &command.labels {
+    for (index, target) in config.targets.iter().enumerate() {
+        for command in &target.commands {
+            for label_name in command.labels.keys() {
                 if !re.is_match(label_name.as_ref()) {
-                    panic!(
-                        "Found illegal character in extra label for target '{}' command '{}'.
-                    '{}' did not match the RegEx specified by the Prometheus specifications.
-                    More information can be found here: https://prometheus.io/docs/concepts/data_model/",
-                        target.name, command.exec, label_name
+                    errors.push(
+                        ConfigError::for_target(
+                            index,
+                            target,
+                            format!(
+                                "The label '{label_name}' of command '{command}' contains illegal characters, see https://prometheus.io/docs/concepts/data_model/"
+                            ),
+                        )
+                        .at_field("commands"),
                     );
                 }
                 if label_name.starts_with("__") {
-                    panic!(
-                        "Found label name starting with '__' in target '{}', command '{}'.
-                        Prometheus reserves label names starting with '__' for internal use.",
-                        target.name, command.exec
+                    errors.push(
+                        ConfigError::for_target(
+                            index,
+                            target,
+                            format!(
+                                "The label '{label_name}' of command '{command}' starts with '__', which Prometheus reserves for internal use"
+                            ),
+                        )
+                        .at_field("commands"),
+                    );
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a label value of a target command contains a placeholder like `{result}` that
+/// does not refer to a named group of the target RegEx.
+fn validate_config_label_placeholders(config: &Schema) -> Vec<ConfigError> {
+    let placeholder_re = Regex::new(r"\{([a-zA-Z_][a-zA-Z0-9_]*)\}").unwrap();
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        // The placeholders of the json parser are checked by validate_config_json
+        if target.parser != OutputParser::Regex {
+            continue;
+        }
+
+        // An invalid RegEx is already reported by validate_config_regex
+        let Ok(regex) = Regex::new(&target.regex) else {
+            continue;
+        };
+        let group_names: HashSet<&str> = regex.capture_names().flatten().collect();
+
+        for command in &target.commands {
+            for (label_name, label_value) in &command.labels {
+                for placeholder in placeholder_re.captures_iter(label_value.as_bytes()) {
+                    let group_name = String::from_utf8_lossy(&placeholder[1]);
+                    if !group_names.contains(group_name.as_ref()) {
+                        errors.push(
+                            ConfigError::for_target(
+                                index,
+                                target,
+                                format!(
+                                    "The label '{label_name}' of command '{command}' uses the placeholder '{{{group_name}}}', but the RegEx does not contain a group called '{group_name}'"
+                                ),
+                            )
+                            .at_field("commands"),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a value group is not a named group of the target RegEx (unless the target uses the
+/// json parser), or if its metric name is not valid or already used by another metric of the target.
+fn validate_config_value_groups(config: &Schema) -> Vec<ConfigError> {
+    let re = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        // An invalid RegEx is already reported by validate_config_regex
+        let regex = match target.parser {
+            OutputParser::Regex => match Regex::new(&target.regex) {
+                Ok(x) => Some(x),
+                Err(_) => continue,
+            },
+            // The JSONPaths are checked by validate_config_json
+            OutputParser::Json => None,
+            // Reported by validate_config_prometheus_parser
+            OutputParser::Prometheus => continue,
+        };
+        let group_names: Option<HashSet<&str>> = regex
+            .as_ref()
+            .map(|regex| regex.capture_names().flatten().collect());
+        let mut metrics: HashSet<&str> = TARGET_METRIC_NAMES.iter().copied().collect();
+
+        let mut value_groups: Vec<_> = target.value_groups.iter().collect();
+        value_groups.sort_by_key(|(group, _)| *group);
+
+        for (group, value_group) in value_groups {
+            let error = |message: String| {
+                ConfigError::for_target(index, target, message).at_field("value_groups")
+            };
+            let metric = value_group.metric();
+
+            if group_names
+                .as_ref()
+                .is_some_and(|group_names| !group_names.contains(group.as_str()))
+            {
+                errors.push(error(format!(
+                    "The RegEx does not contain a group called '{group}'"
+                )));
+            }
+            if !re.is_match(format!("{}_{metric}", target.name).as_bytes()) {
+                errors.push(error(format!(
+                    "The metric '{metric}' of the group '{group}' contains illegal characters, see https://prometheus.io/docs/concepts/data_model/"
+                )));
+            }
+            // prometheus-client adds the suffix of counters, so it does not tell metrics apart
+            let metric_name = match target.metric_type {
+                MetricType::Counter => metric.strip_suffix("_total").unwrap_or(metric),
+                _ => metric,
+            };
+            if !metrics.insert(metric_name) {
+                errors.push(error(format!(
+                    "The metric '{metric}' of the group '{group}' is already used by another metric of the target"
+                )));
+            }
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a target that uses the json parser has no value or an invalid JSONPath in
+/// `json_items`, `json_value`, `value_groups` or the placeholders of the command labels.
+fn validate_config_json(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if target.parser != OutputParser::Json {
+            continue;
+        }
+
+        if target.json_value.is_none() && target.value_groups.is_empty() {
+            errors.push(
+                ConfigError::for_target(
+                    index,
+                    target,
+                    "The target needs a 'json_value' or 'value_groups'",
+                )
+                .at_field("parser"),
+            );
+        }
+
+        let mut paths = vec![];
+        if let Some(json_items) = &target.json_items {
+            paths.push(("json_items", json_items.as_str()));
+        }
+        if let Some(json_value) = &target.json_value {
+            paths.push(("json_value", json_value.as_str()));
+        }
+        let mut value_groups: Vec<&String> = target.value_groups.keys().collect();
+        value_groups.sort();
+        for path in value_groups {
+            paths.push(("value_groups", path));
+        }
+        for command in &target.commands {
+            for label_value in command.labels.values() {
+                for placeholder in json_parser::PLACEHOLDER_REGEX.captures_iter(label_value) {
+                    paths.push(("commands", placeholder.get(1).unwrap().as_str()));
+                }
+            }
+        }
+
+        for (field, path) in paths {
+            if let Err(e) = json_parser::parse_path(path) {
+                errors.push(ConfigError::for_target(index, target, e).at_field(field));
+            }
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a target exports a state set without a `value_map`.
+fn validate_config_value_map(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if target.state_set && target.value_map.is_empty() {
+            errors.push(
+                ConfigError::for_target(
+                    index,
+                    target,
+                    "A state set needs the words of the 'value_map' as its states",
+                )
+                .at_field("state_set"),
+            );
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If the `transform` of a target is not a valid expression, uses variables other than `value`
+/// and the named groups of the RegEx, or if the target has no result to transform.
+fn validate_config_transform(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        let Some(transform) = &target.transform else {
+            continue;
+        };
+        let error =
+            |message: String| ConfigError::for_target(index, target, message).at_field("transform");
+
+        let mut variables = vec![transform::VALUE_VARIABLE.to_string()];
+        match target.parser {
+            // An invalid RegEx is already reported by validate_config_regex
+            OutputParser::Regex => match Regex::new(&target.regex) {
+                Ok(regex) => variables.extend(regex.capture_names().flatten().map(str::to_owned)),
+                Err(_) => continue,
+            },
+            OutputParser::Json => (),
+            // Reported by validate_config_prometheus_parser
+            OutputParser::Prometheus => continue,
+        }
+
+        if target.result_selector().is_none() {
+            errors.push(error(
+                "The transform converts the result, but the target has no 'regex_named_group' or 'json_value'".to_string(),
+            ));
+        }
+        if let Err(e) = transform::check_transform(transform, &variables) {
+            errors.push(error(e));
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If the `buckets` of a target are set without the histogram type, are empty or not increasing.
+fn validate_config_metric_type(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        let Some(buckets) = &target.buckets else {
+            continue;
+        };
+        let error =
+            |message: &str| ConfigError::for_target(index, target, message).at_field("buckets");
+        if target.metric_type != MetricType::Histogram {
+            errors.push(error(
+                "Buckets can only be set if the metric type is histogram",
+            ));
+        } else if let Err(e) = check_buckets(buckets) {
+            errors.push(error(e));
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a target that uses the prometheus parser sets fields that only apply to the values
+/// that are parsed by the other parsers.
+fn validate_config_prometheus_parser(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if target.parser != OutputParser::Prometheus {
+            continue;
+        }
+
+        let fields: Vec<&str> = [
+            ("value_groups", !target.value_groups.is_empty()),
+            ("value_map", !target.value_map.is_empty()),
+            ("state_set", target.state_set),
+            ("transform", target.transform.is_some()),
+            ("metric_type", target.metric_type != MetricType::Gauge),
+        ]
+        .into_iter()
+        .filter_map(|(field, is_set)| is_set.then_some(field))
+        .collect();
+        let Some(first_field) = fields.first() else {
+            continue;
+        };
+
+        let fields: Vec<String> = fields.iter().map(|field| format!("'{field}'")).collect();
+        errors.push(
+            ConfigError::for_target(
+                index,
+                target,
+                format!(
+                    "The prometheus parser serves the metrics of the commands as they are and does not support {}",
+                    fields.join(", ")
+                ),
+            )
+            .at_field(first_field),
+        );
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If the `duration_buckets` of a target are empty or not increasing.
+fn validate_config_duration_buckets(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if let Some(Err(e)) = target.duration_buckets.as_deref().map(check_buckets) {
+            errors.push(ConfigError::for_target(index, target, e).at_field("duration_buckets"));
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If the series of a target become stale after zero runs.
+fn validate_config_stale_after(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if target.stale_after == Some(StaleAfter::Runs(0)) {
+            errors.push(
+                ConfigError::for_target(
+                    index,
+                    target,
+                    "The series need to become stale after at least one run without an update",
+                )
+                .at_field("stale_after"),
+            );
+        }
+    }
+
+    errors
+}
+
+/// Checks the upper bounds of the buckets of a histogram
+fn check_buckets(buckets: &[f64]) -> Result<(), &'static str> {
+    if buckets.is_empty() {
+        Err("A histogram needs at least one bucket")
+    } else if buckets.iter().any(|bucket| !bucket.is_finite())
+        || buckets.windows(2).any(|pair| pair[0] >= pair[1])
+    {
+        Err("The upper bounds of the buckets must be finite and increasing, the +Inf bucket is added automatically")
+    } else {
+        Ok(())
+    }
+}
+
+/// ## Errors
+/// If a target does not have exactly one of `run_every` and `schedule`, or if the
+/// cron expression or time zone of a target is invalid.
+fn validate_config_schedule(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if let Err(err) = Schedule::from_target(target) {
+            let field = match target.schedule {
+                Some(_) => "schedule",
+                None => "run_every",
+            };
+            errors.push(ConfigError::for_target(index, target, err).at_field(field));
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a concurrency limit is zero or a target references a concurrency group that does not exist.
+fn validate_config_concurrency(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    if config.max_concurrent_commands == Some(0) {
+        errors.push(
+            ConfigError::new("The limit has to be greater than zero")
+                .at_field("max_concurrent_commands"),
+        );
+    }
+
+    for (group, limit) in &config.concurrency_groups {
+        if *limit == 0 {
+            errors.push(
+                ConfigError::new(format!(
+                    "The limit of group '{group}' has to be greater than zero"
+                ))
+                .at_field("concurrency_groups"),
+            );
+        }
+    }
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if let Some(group) = &target.concurrency_group {
+            if !config.concurrency_groups.contains_key(group) {
+                errors.push(
+                    ConfigError::for_target(
+                        index,
+                        target,
+                        format!("The concurrency group '{group}' is not defined in 'concurrency_groups'"),
+                    )
+                    .at_field("concurrency_group"),
+                );
+            }
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If a command does not have exactly one of `exec` and `argv`, `argv` is empty
+/// or the shell of a target is empty.
+fn validate_config_commands(config: &Schema) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        if let Some(shell) = &target.shell {
+            if shell
+                .first()
+                .filter(|program| !program.is_empty())
+                .is_none()
+            {
+                errors.push(
+                    ConfigError::for_target(index, target, "The shell is empty").at_field("shell"),
+                );
+            }
+        }
+
+        for command in &target.commands {
+            let message = match (&command.exec, &command.argv) {
+                (Some(_), Some(_)) => {
+                    format!("Command '{command}' can only have one of 'exec' and 'argv'")
+                }
+                (None, None) => "A command has neither 'exec' nor 'argv'".to_string(),
+                (None, Some(argv)) if argv.is_empty() => {
+                    "A command has an empty 'argv'".to_string()
+                }
+                _ => continue,
+            };
+            errors.push(ConfigError::for_target(index, target, message).at_field("commands"));
+        }
+    }
+
+    errors
+}
+
+/// ## Errors
+/// If the working directory of a command does not exist or the name of
+/// an environment variable is not valid.
+fn validate_config_command_environment(config: &Schema) -> Vec<ConfigError> {
+    let re = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+    let mut errors = vec![];
+
+    for (index, target) in config.targets.iter().enumerate() {
+        for command in &target.commands {
+            for env_name in command.env.keys() {
+                if !re.is_match(env_name.as_ref()) {
+                    errors.push(
+                        ConfigError::for_target(
+                            index,
+                            target,
+                            format!(
+                                "The environment variable name '{env_name}' of command '{command}' is not valid. Names may only contain letters, digits and '_' and must not start with a digit."
+                            ),
+                        )
+                        .at_field("commands"),
+                    );
+                }
+            }
+
+            if let Some(cwd) = &command.cwd {
+                if !Path::new(cwd).is_dir() {
+                    errors.push(
+                        ConfigError::for_target(
+                            index,
+                            target,
+                            format!("The working directory '{cwd}' of command '{command}' is not a directory"),
+                        )
+                        .at_field("commands"),
                     );
                 }
             }
         }
     }
+
+    errors
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::schema::{Target, TargetCommand};
+    use crate::config::schema::{Target, TargetCommand, ValueGroup};
+    use std::collections::HashMap;
+
+    #[test]
+    fn target_legacy_duration_gauge_default() {
+        let yaml = "name: disk\ncommands: []\nsuccess_exit_codes: [0]\n";
+        let target: Target = serde_yml::from_str(yaml).unwrap();
+        assert!(target.legacy_duration_gauge);
+
+        let yaml = format!("{yaml}legacy_duration_gauge: false");
+        let target: Target = serde_yml::from_str(&yaml).unwrap();
+        assert!(!target.legacy_duration_gauge);
+    }
 
     #[test]
     fn validate_config_labels_valid() {
@@ -219,16 +838,17 @@ mod tests {
                 regex_named_group: "".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_labels(&config);
+        assert!(validate_config_labels(&config).is_empty());
     }
 
     #[test]
-    #[should_panic]
     fn validate_config_labels_invalid() {
         let config = Schema {
             targets: vec![Target {
@@ -238,12 +858,14 @@ mod tests {
                 regex_named_group: "".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_labels(&config);
+        assert_eq!(validate_config_labels(&config).len(), 1);
     }
 
     #[test]
@@ -256,16 +878,17 @@ mod tests {
                 regex_named_group: "result".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_regex(&config);
+        assert!(validate_config_regex(&config).is_empty());
     }
 
     #[test]
-    #[should_panic]
     fn validate_config_regex_invalid_regex() {
         let config = Schema {
             targets: vec![Target {
@@ -275,16 +898,17 @@ mod tests {
                 regex_named_group: "result".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_regex(&config);
+        assert_eq!(validate_config_regex(&config).len(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn validate_config_regex_missing_group() {
         let config = Schema {
             targets: vec![Target {
@@ -294,58 +918,62 @@ mod tests {
                 regex_named_group: "result".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_regex(&config);
+        assert_eq!(validate_config_regex(&config).len(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn validate_config_command_labels_invalid_regex() {
         let config = Schema {
             targets: vec![Target {
                 name: "".to_string(),
                 commands: vec![TargetCommand {
-                    exec: "".to_string(),
-                    labels: vec![("$$§21invalid.label".to_string(), "".to_string())],
+                    exec: Some("".to_string()),
+                    labels: HashMap::from([("$$§21invalid.label".to_string(), "".to_string())]),
+                    ..Default::default()
                 }],
                 regex: "".to_string(),
                 regex_named_group: "".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        // This should panic
-        validate_config_command_labels(&config);
+        assert_eq!(validate_config_command_labels(&config).len(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn validate_config_command_labels_invalid_prefix() {
         let config = Schema {
             targets: vec![Target {
                 name: "".to_string(),
                 commands: vec![TargetCommand {
-                    exec: "".to_string(),
-                    labels: vec![("__invalid_label".to_string(), "".to_string())],
+                    exec: Some("".to_string()),
+                    labels: HashMap::from([("__invalid_label".to_string(), "".to_string())]),
+                    ..Default::default()
                 }],
                 regex: "".to_string(),
                 regex_named_group: "".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        // This should panic
-        validate_config_command_labels(&config);
+        assert_eq!(validate_config_command_labels(&config).len(), 1);
     }
 
     #[test]
@@ -354,18 +982,645 @@ mod tests {
             targets: vec![Target {
                 name: "".to_string(),
                 commands: vec![TargetCommand {
-                    exec: "".to_string(),
-                    labels: vec![("valid_label".to_string(), "".to_string())],
+                    exec: Some("".to_string()),
+                    labels: HashMap::from([("valid_label".to_string(), "".to_string())]),
+                    ..Default::default()
                 }],
                 regex: "".to_string(),
                 regex_named_group: "".to_string(),
                 success_exit_codes: vec![],
                 run_every: Default::default(),
+                ..Default::default()
+            }],
+            host: "".to_string(),
+            port: 0,
+            ..Default::default()
+        };
+
+        assert!(validate_config_command_labels(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_label_placeholders_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "".to_string(),
+                commands: vec![TargetCommand {
+                    exec: Some("".to_string()),
+                    labels: HashMap::from([("status".to_string(), "{status}".to_string())]),
+                    ..Default::default()
+                }],
+                regex: r"(?<status>\w+) (?<result>\d+)".to_string(),
+                regex_named_group: "result".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_label_placeholders(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_label_placeholders_unknown_group() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "".to_string(),
+                commands: vec![TargetCommand {
+                    exec: Some("".to_string()),
+                    labels: HashMap::from([("status".to_string(), "{state}".to_string())]),
+                    ..Default::default()
+                }],
+                regex: r"(?<status>\w+) (?<result>\d+)".to_string(),
+                regex_named_group: "result".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_label_placeholders(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_regex_value_groups_only() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "df".to_string(),
+                regex: r"(?<used>\d+) (?<avail>\d+)".to_string(),
+                value_groups: HashMap::from([(
+                    "used".to_string(),
+                    ValueGroup::Name("bytes_used".to_string()),
+                )]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_regex(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_regex_no_value() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "df".to_string(),
+                regex: r"(?<used>\d+)".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_regex(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_value_groups_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "df".to_string(),
+                regex: r"(?<used>\d+) (?<avail>\d+)".to_string(),
+                value_groups: HashMap::from([
+                    (
+                        "used".to_string(),
+                        ValueGroup::Name("bytes_used".to_string()),
+                    ),
+                    (
+                        "avail".to_string(),
+                        ValueGroup::Metric {
+                            metric: "bytes_available".to_string(),
+                            help: Some("Available bytes".to_string()),
+                        },
+                    ),
+                ]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_value_groups(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_value_groups_invalid() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "df".to_string(),
+                regex: r"(?<used>\d+) (?<avail>\d+)".to_string(),
+                value_groups: HashMap::from([
+                    // Not a group of the RegEx
+                    ("free".to_string(), ValueGroup::Name("free".to_string())),
+                    // Illegal characters
+                    (
+                        "used".to_string(),
+                        ValueGroup::Name("bytes-used".to_string()),
+                    ),
+                    // Collides with a metric of the target
+                    ("avail".to_string(), ValueGroup::Name("result".to_string())),
+                ]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_value_groups(&config).len(), 3);
+    }
+
+    #[test]
+    fn validate_config_json_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "lsblk".to_string(),
+                parser: OutputParser::Json,
+                json_items: Some("$.blockdevices[*]".to_string()),
+                json_value: Some("$.size".to_string()),
+                commands: vec![TargetCommand {
+                    exec: Some("lsblk -J -b".to_string()),
+                    labels: HashMap::from([("device".to_string(), "{$.name}".to_string())]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_json(&config).is_empty());
+        assert!(validate_config_regex(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_json_invalid_path() {
+        let config = Schema {
+            targets: vec![Target {
+                name: "lsblk".to_string(),
+                parser: OutputParser::Json,
+                json_items: Some("$.blockdevices[".to_string()),
+                commands: vec![TargetCommand {
+                    exec: Some("lsblk -J -b".to_string()),
+                    labels: HashMap::from([("device".to_string(), "{$..[}".to_string())]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        // The invalid paths and the missing value
+        assert_eq!(validate_config_json(&config).len(), 3);
+    }
+
+    #[test]
+    fn validate_config_value_map_state_set() {
+        let target = Target {
+            name: "unit".to_string(),
+            regex: r"(?<result>\w+)".to_string(),
+            regex_named_group: "result".to_string(),
+            state_set: true,
+            ..Default::default()
+        };
+        let config = Schema {
+            targets: vec![
+                target.clone(),
+                Target {
+                    value_map: HashMap::from([("active".to_string(), 1.0)]),
+                    ..target.clone()
+                },
+                Target {
+                    parser: OutputParser::Prometheus,
+                    value_map: HashMap::from([("active".to_string(), 1.0)]),
+                    ..target
+                },
+            ],
+            ..Default::default()
+        };
+
+        let errors = validate_config_value_map(&config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].target_index, Some(0));
+    }
+
+    #[test]
+    fn validate_config_transform_variables() {
+        let target = Target {
+            name: "du".to_string(),
+            regex: r"(?<result>\d+) (?<limit>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            transform: Some("min(value * 1024, limit)".to_string()),
+            ..Default::default()
+        };
+        let config = Schema {
+            targets: vec![
+                target.clone(),
+                Target {
+                    transform: Some("value * unknown".to_string()),
+                    ..target.clone()
+                },
+                Target {
+                    regex_named_group: String::new(),
+                    ..target
+                },
+            ],
+            ..Default::default()
+        };
+
+        let errors = validate_config_transform(&config);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].target_index, Some(1));
+        assert_eq!(errors[1].target_index, Some(2));
+    }
+
+    #[test]
+    fn validate_config_metric_type_buckets() {
+        let target = Target {
+            name: "latency".to_string(),
+            metric_type: MetricType::Histogram,
+            buckets: Some(vec![0.1, 0.5, 1.0]),
+            ..Default::default()
+        };
+        let config = Schema {
+            targets: vec![
+                target.clone(),
+                Target {
+                    buckets: Some(vec![1.0, 0.5]),
+                    ..target.clone()
+                },
+                Target {
+                    metric_type: MetricType::Counter,
+                    ..target
+                },
+            ],
+            ..Default::default()
+        };
+
+        let errors = validate_config_metric_type(&config);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].target_index, Some(1));
+        assert_eq!(errors[1].target_index, Some(2));
+    }
+
+    #[test]
+    fn validate_config_duration_buckets_invalid() {
+        let target = Target {
+            name: "backup".to_string(),
+            duration_buckets: Some(vec![1.0, 60.0, 3600.0]),
+            ..Default::default()
+        };
+        let config = Schema {
+            targets: vec![
+                target.clone(),
+                Target {
+                    duration_buckets: Some(vec![]),
+                    ..target.clone()
+                },
+                Target {
+                    duration_buckets: Some(vec![1.0, f64::INFINITY]),
+                    ..target
+                },
+            ],
+            ..Default::default()
+        };
+
+        let errors = validate_config_duration_buckets(&config);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].target_index, Some(1));
+    }
+
+    #[test]
+    fn validate_config_prometheus_parser_fields() {
+        let target = Target {
+            name: "textfile".to_string(),
+            parser: OutputParser::Prometheus,
+            ..Default::default()
+        };
+        let config = Schema {
+            targets: vec![
+                target.clone(),
+                Target {
+                    value_groups: HashMap::from([(
+                        "used".to_string(),
+                        ValueGroup::Name("bytes_used".to_string()),
+                    )]),
+                    transform: Some("value * 2".to_string()),
+                    metric_type: MetricType::Counter,
+                    ..target
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert!(validate_config_regex(&config).is_empty());
+        assert!(validate_config_value_groups(&config).is_empty());
+        assert!(validate_config_transform(&config).is_empty());
+
+        let errors = validate_config_prometheus_parser(&config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].target_index, Some(1));
+        assert_eq!(errors[0].field, Some("value_groups"));
+        assert!(errors[0]
+            .message
+            .ends_with("'value_groups', 'transform', 'metric_type'"));
+    }
+
+    #[test]
+    fn validate_config_schedule_valid() {
+        let config = Schema {
+            targets: vec![
+                Target {
+                    run_every: Some("5s".parse().unwrap()),
+                    ..Default::default()
+                },
+                Target {
+                    schedule: Some("0 */6 * * *".to_string()),
+                    timezone: Some("Europe/Berlin".to_string()),
+                    ..Default::default()
+                },
+            ],
+            host: "".to_string(),
+            port: 0,
+            ..Default::default()
+        };
+
+        assert!(validate_config_schedule(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_schedule_missing() {
+        let config = Schema {
+            targets: vec![Target::default()],
+            host: "".to_string(),
+            port: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_schedule(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_schedule_both() {
+        let config = Schema {
+            targets: vec![Target {
+                run_every: Some("5s".parse().unwrap()),
+                schedule: Some("0 */6 * * *".to_string()),
+                ..Default::default()
+            }],
+            host: "".to_string(),
+            port: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_schedule(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_schedule_invalid_cron() {
+        let config = Schema {
+            targets: vec![Target {
+                schedule: Some("0 */6 * *".to_string()),
+                ..Default::default()
             }],
             host: "".to_string(),
             port: 0,
+            ..Default::default()
         };
 
-        validate_config_command_labels(&config);
+        assert_eq!(validate_config_schedule(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_schedule_invalid_timezone() {
+        let config = Schema {
+            targets: vec![Target {
+                schedule: Some("0 */6 * * *".to_string()),
+                timezone: Some("Mars/Olympus_Mons".to_string()),
+                ..Default::default()
+            }],
+            host: "".to_string(),
+            port: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_schedule(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_concurrency_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                concurrency_group: Some("storage_box".to_string()),
+                ..Default::default()
+            }],
+            max_concurrent_commands: Some(4),
+            concurrency_groups: HashMap::from([("storage_box".to_string(), 2)]),
+            ..Default::default()
+        };
+
+        assert!(validate_config_concurrency(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_concurrency_unknown_group() {
+        let config = Schema {
+            targets: vec![Target {
+                concurrency_group: Some("storage_box".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_concurrency(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_concurrency_zero_limit() {
+        let config = Schema {
+            concurrency_groups: HashMap::from([("storage_box".to_string(), 0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_concurrency(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_commands_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![
+                    TargetCommand {
+                        exec: Some("echo 42".to_string()),
+                        ..Default::default()
+                    },
+                    TargetCommand {
+                        argv: Some(vec!["echo".to_string(), "42".to_string()]),
+                        ..Default::default()
+                    },
+                ],
+                shell: Some(
+                    ["bash", "-eo", "pipefail", "-c"]
+                        .map(str::to_string)
+                        .to_vec(),
+                ),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_commands(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_commands_exec_and_argv() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![TargetCommand {
+                    exec: Some("echo 42".to_string()),
+                    argv: Some(vec!["echo".to_string(), "42".to_string()]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_commands(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_commands_empty_argv() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![TargetCommand {
+                    argv: Some(vec![]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_commands(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_command_environment_valid() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![TargetCommand {
+                    env: HashMap::from([("PGPASSWORD".to_string(), "secret".to_string())]),
+                    cwd: Some(".".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_config_command_environment(&config).is_empty());
+    }
+
+    #[test]
+    fn validate_config_command_environment_invalid_name() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![TargetCommand {
+                    env: HashMap::from([("1NVALID=NAME".to_string(), "".to_string())]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_command_environment(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_command_environment_missing_cwd() {
+        let config = Schema {
+            targets: vec![Target {
+                commands: vec![TargetCommand {
+                    cwd: Some("./does/not/exist".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_command_environment(&config).len(), 1);
+    }
+
+    #[test]
+    fn validate_config_target_names_duplicate() {
+        let config = Schema {
+            targets: vec![
+                Target {
+                    name: "the_answer".to_string(),
+                    ..Default::default()
+                },
+                Target {
+                    name: "the_answer".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_config_target_names(&config).len(), 1);
+    }
+
+    #[test]
+    fn parse_cfg_reports_all_errors_with_location() {
+        let config = r#"host: 127.0.0.1
+port: 8080
+max_concurrent_commands: 0
+targets:
+  - name: "valid"
+    commands:
+      - exec: echo 42
+    regex: '(?<result>.*)'
+    regex_named_group: result
+    success_exit_codes: [ 0 ]
+    run_every: 5s
+
+  - name: "invalid-name"
+    commands:
+      - exec: echo 42
+    regex: '(?<result.*)'
+    regex_named_group: result
+    success_exit_codes: [ 0 ]
+    run_every: 5s
+"#;
+        let cli_args = CliArgs::default();
+
+        let errors = parse_cfg(config, &cli_args).unwrap_err().0;
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|e| {
+                (
+                    e.target_index,
+                    e.field,
+                    e.location.map(|l| (l.line, l.column)),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            locations,
+            vec![
+                (None, Some("max_concurrent_commands"), Some((3, 1))),
+                (Some(1), Some("name"), Some((13, 5))),
+                (Some(1), Some("regex"), Some((16, 5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_cfg_reports_yaml_error_location() {
+        let cli_args = CliArgs::default();
+
+        let errors = parse_cfg("host: 127.0.0.1\nport: [\n", &cli_args)
+            .unwrap_err()
+            .0;
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].location.is_some());
     }
 }
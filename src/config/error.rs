@@ -0,0 +1,91 @@
+use crate::config::schema::Target;
+use std::fmt::{Display, Formatter};
+
+/// A position in the config file, both values start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A problem that was found while reading or validating the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// The index of the target in the `targets` list, if the problem belongs to a target
+    pub target_index: Option<usize>,
+    /// The name of the target, if the problem belongs to a target
+    pub target_name: Option<String>,
+    /// The name of the field that caused the problem, used to find its location
+    pub field: Option<&'static str>,
+    /// Where the problem is in the config file, if it could be found
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+impl ConfigError {
+    /// A problem that does not belong to a target
+    pub fn new(message: impl Into<String>) -> Self {
+        ConfigError {
+            target_index: None,
+            target_name: None,
+            field: None,
+            location: None,
+            message: message.into(),
+        }
+    }
+
+    /// A problem of the target with the index in the `targets` list
+    pub fn for_target(index: usize, target: &Target, message: impl Into<String>) -> Self {
+        ConfigError {
+            target_index: Some(index),
+            target_name: Some(target.name.clone()),
+            ..Self::new(message)
+        }
+    }
+
+    /// Sets the field that caused the problem
+    pub fn at_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "line {}, column {}: ", location.line, location.column)?;
+        }
+
+        match (&self.target_index, &self.target_name) {
+            (Some(index), Some(name)) => write!(f, "target #{index} '{name}': ")?,
+            (Some(index), None) => write!(f, "target #{index}: ")?,
+            _ => (),
+        }
+
+        if let Some(field) = &self.field {
+            write!(f, "'{field}': ")?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+/// All problems that were found in the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl From<ConfigError> for ConfigErrors {
+    fn from(value: ConfigError) -> Self {
+        ConfigErrors(vec![value])
+    }
+}
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Found {} problem(s) in the config file:", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
@@ -1,39 +1,291 @@
 use duration_string::DurationString;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 /// The config file schema
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct Schema {
     pub targets: Vec<Target>,
     pub host: String,
     pub port: u16,
+    /// The maximum number of commands that are executed at the same time
+    pub max_concurrent_commands: Option<usize>,
+    /// Named groups that limit the number of commands of their targets that are
+    /// executed at the same time
+    #[serde(default)]
+    pub concurrency_groups: HashMap<String, usize>,
 }
 
 /// A command that should be executed and parsed
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct Target {
     /// The name of the target
     pub name: String,
     /// The shell command to execute
     pub commands: Vec<TargetCommand>,
+    /// How the stdout of the commands is parsed
+    #[serde(default)]
+    pub parser: OutputParser,
     /// The regex to parse the stdout
+    #[serde(default)]
     pub regex: String,
     /// The named group in the regex containing the result
+    #[serde(default)]
     pub regex_named_group: String,
+    /// Additional named groups in the regex that are exported as separate metrics
+    #[serde(default)]
+    pub value_groups: HashMap<String, ValueGroup>,
+    /// How the values are written in the output, e.g. with a unit like `1.5G`
+    #[serde(default)]
+    pub value_format: ValueFormat,
+    /// Words in the output that are mapped to a number, e.g. `active: 1`
+    #[serde(default)]
+    pub value_map: HashMap<String, f64>,
+    /// Export the words of the `value_map` as a state set with one series per word
+    #[serde(default)]
+    pub state_set: bool,
+    /// An expression that converts the parsed result, e.g. `value * 1024`
+    pub transform: Option<String>,
+    /// The type of the metrics of the result and the `value_groups`
+    #[serde(default)]
+    pub metric_type: MetricType,
+    /// The upper bounds of the buckets if the metric type is histogram
+    pub buckets: Option<Vec<f64>>,
+    /// Whether only the first match of the regex or every match creates a series
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// The JSONPath of the items in the output, every item creates a series (json parser only)
+    pub json_items: Option<String>,
+    /// The JSONPath of the result in an item (json parser only)
+    pub json_value: Option<String>,
     /// A list of exit codes that indicate a successful execution
     pub success_exit_codes: Vec<i32>,
     /// The interval to execute the command in
-    pub run_every: DurationString,
+    pub run_every: Option<DurationString>,
+    /// What to do if executions were missed, e.g. because the system was suspended
+    #[serde(default)]
+    pub missed_tick_behavior: MissedTickBehavior,
+    /// A cron expression to execute the command at, alternative to `run_every`
+    pub schedule: Option<String>,
+    /// The IANA time zone of the cron expression, the local time zone is used if not set
+    pub timezone: Option<String>,
+    /// The time after which a command of this target is killed
+    pub timeout: Option<DurationString>,
+    /// What to do if the target is due while its previous run is still in progress
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    /// The name of the concurrency group that limits the commands of this target
+    pub concurrency_group: Option<String>,
+    /// The shell and its arguments that run the `exec` commands, e.g. `[bash, -eo, pipefail, -c]`
+    pub shell: Option<Vec<String>>,
+    /// The upper bounds of the buckets of the duration histogram in seconds
+    pub duration_buckets: Option<Vec<f64>>,
+    /// Keep exporting the duration of the last execution in milliseconds, enabled if not set
+    #[serde(default = "default_true")]
+    pub legacy_duration_gauge: bool,
+    /// When the series of the results are removed, because they were not updated
+    pub stale_after: Option<StaleAfter>,
+    /// Add the exit code of the command as a label to the series of the results
+    #[serde(default)]
+    pub exit_code_label: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Target {
+    /// The named group or JSONPath that selects the value of the result metric
+    pub fn result_selector(&self) -> Option<&str> {
+        match self.parser {
+            OutputParser::Regex if self.regex_named_group.is_empty() => None,
+            OutputParser::Regex => Some(&self.regex_named_group),
+            OutputParser::Json => self.json_value.as_deref(),
+            OutputParser::Prometheus => None,
+        }
+    }
+
+    /// The named groups or JSONPaths of all values that are parsed from the output.
+    /// The result comes first, the value groups are sorted by name.
+    pub fn value_selectors(&self) -> Vec<&str> {
+        let mut value_groups: Vec<&str> = self.value_groups.keys().map(String::as_str).collect();
+        value_groups.sort();
+
+        self.result_selector()
+            .into_iter()
+            .chain(value_groups)
+            .collect()
+    }
 }
 
 /// A command that should be executed and parsed
 /// This is a separate struct to allow to add labels to the metric that is created
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct TargetCommand {
-    /// The command to execute
-    pub exec: String,
+    /// The command line to execute in a shell
+    pub exec: Option<String>,
+    /// The program and its arguments to execute without a shell, alternative to `exec`
+    pub argv: Option<Vec<String>>,
     /// A list of additional labels to add to the metric
     /// The values can be templated with the result of the command regex
+    #[serde(default)]
     pub labels: HashMap<String, String>,
+    /// The time after which the command is killed, overrides the timeout of the target
+    pub timeout: Option<DurationString>,
+    /// Additional environment variables of the command
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Do not pass the environment of this program to the command, only `env` is set
+    #[serde(default)]
+    pub clear_env: bool,
+    /// The working directory of the command
+    pub cwd: Option<String>,
+    /// A text that is written to the standard input of the command
+    pub stdin: Option<String>,
+}
+
+impl Display for TargetCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.exec, &self.argv) {
+            (Some(exec), _) => write!(f, "{exec}"),
+            (None, Some(argv)) => write!(f, "{argv:?}"),
+            (None, None) => write!(f, "<empty>"),
+        }
+    }
+}
+
+/// The metric a named group of the regex is exported as
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(untagged)]
+pub enum ValueGroup {
+    /// Only the name of the metric
+    Name(String),
+    /// The name of the metric and its help text
+    Metric {
+        metric: String,
+        help: Option<String>,
+    },
+}
+
+impl ValueGroup {
+    /// The name of the metric without the target name prefix
+    pub fn metric(&self) -> &str {
+        match self {
+            ValueGroup::Name(metric) => metric,
+            ValueGroup::Metric { metric, .. } => metric,
+        }
+    }
+
+    pub fn help(&self) -> Option<&str> {
+        match self {
+            ValueGroup::Name(_) => None,
+            ValueGroup::Metric { help, .. } => help.as_deref(),
+        }
+    }
+}
+
+/// Decides how the stdout of the commands is parsed
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputParser {
+    /// The values are selected with the named groups of `regex`
+    #[default]
+    Regex,
+    /// The output is JSON, the values are selected with JSONPath expressions
+    Json,
+    /// The output is in the Prometheus text format or OpenMetrics and is served as it is
+    Prometheus,
+}
+
+/// The format of the values in the output of the commands.
+/// The values are converted to the base unit, e.g. bytes, seconds or a ratio.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueFormat {
+    /// A number like `1234.5` or `1.2e3`
+    #[default]
+    Plain,
+    /// Bytes with a decimal unit like `1.5G` or `512kB` (1k = 1000)
+    BytesSi,
+    /// Bytes with a binary unit like `1.5G` or `512KiB` (1K = 1024)
+    BytesIec,
+    /// A duration like `3d4h`, `250ms` or `01:02:03`, converted to seconds
+    Duration,
+    /// A percentage like `12%`, converted to a ratio between 0 and 1
+    Percent,
+    /// A number with thousands separators like `1,234,567`
+    ThousandsSeparated,
+    /// A number that is formatted according to the locale of the command, like `1.234,5`
+    Locale,
+}
+
+/// Decides which matches of the regex in the output of a command are used
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// Only the first match is used
+    #[default]
+    First,
+    /// Every match creates a series, e.g. one for every line of the output
+    All,
+}
+
+/// The type of the metrics the parsed values are exported as
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricType {
+    /// The last parsed value
+    #[default]
+    Gauge,
+    /// A monotonically increasing total. If the value decreases, the counting is
+    /// assumed to have restarted, e.g. after a reboot.
+    Counter,
+    /// A histogram of all values that were parsed
+    Histogram,
+}
+
+/// Decides when the series of a target are removed, because they were not updated
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum StaleAfter {
+    /// After this number of runs of the target without an update
+    Runs(u64),
+    /// After an event of the command that created the series
+    Event(StaleEvent),
+}
+
+/// The events of a command that make its series stale
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StaleEvent {
+    /// The command did not succeed
+    OnFailure,
+}
+
+/// Decides how the scheduler catches up with executions it missed
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedTickBehavior {
+    /// Run all missed executions as fast as possible
+    Burst,
+    /// Run once immediately and continue the interval from then on
+    Delay,
+    /// Run once immediately and continue on the original cadence
+    #[default]
+    Skip,
+}
+
+/// Decides what happens if a target is due while its previous run is still in progress
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Do not start the new run
+    #[default]
+    Skip,
+    /// Start the new run after the previous one finished.
+    /// At most one run waits, further runs are skipped.
+    Queue,
+    /// Kill the previous run and start the new one
+    KillPrevious,
 }
@@ -0,0 +1,210 @@
+use crate::config::error::Location;
+
+/// Finds the locations of the targets and their fields in the text of the config file.
+///
+/// This is a best effort line based search that only understands block style YAML
+/// (which is used by all examples). If something cannot be found, the errors are
+/// simply reported without a location.
+pub struct SourceMap<'a> {
+    lines: Vec<&'a str>,
+    /// The line index of every item in the `targets` list
+    target_lines: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(text: &'a str) -> Self {
+        let lines: Vec<&str> = text.lines().collect();
+        let mut target_lines = vec![];
+
+        let targets_line = lines
+            .iter()
+            .position(|line| indentation(line) == 0 && line.starts_with("targets:"));
+
+        if let Some(targets_line) = targets_line {
+            let mut item_column = None;
+
+            for (index, line) in lines.iter().enumerate().skip(targets_line + 1) {
+                if is_blank(line) {
+                    continue;
+                }
+
+                let column = indentation(line);
+                let is_item = line[column..].starts_with('-');
+                match item_column {
+                    None if is_item => {
+                        item_column = Some(column);
+                        target_lines.push(index);
+                    }
+                    Some(item_column) if is_item && column == item_column => {
+                        target_lines.push(index)
+                    }
+                    // Everything that is indented deeper belongs to the current item
+                    Some(item_column) if column > item_column => (),
+                    _ => break,
+                }
+            }
+        }
+
+        SourceMap {
+            lines,
+            target_lines,
+        }
+    }
+
+    /// Finds a key at the top level of the file
+    pub fn locate_key(&self, key: &str) -> Option<Location> {
+        self.find_key(0..self.lines.len(), 0, key)
+    }
+
+    /// Finds the start of a target or one of its keys
+    pub fn locate_target(&self, target_index: usize, key: Option<&str>) -> Option<Location> {
+        let start = *self.target_lines.get(target_index)?;
+        let end = match self.target_lines.get(target_index + 1) {
+            Some(next) => *next,
+            None => self.lines.len(),
+        };
+
+        let column = indentation(self.lines[start]);
+        let target_location = Location {
+            line: start + 1,
+            column: column + 1,
+        };
+
+        // The keys of the target are aligned with its first key after the dash
+        let key_column = column + item_key_offset(&self.lines[start][column..]);
+        match key {
+            None => Some(target_location),
+            Some(key) => self
+                .find_key(start..end, key_column, key)
+                .or(Some(target_location)),
+        }
+    }
+
+    fn find_key(
+        &self,
+        lines: std::ops::Range<usize>,
+        column: usize,
+        key: &str,
+    ) -> Option<Location> {
+        for index in lines {
+            let line = self.lines[index];
+            if line.len() <= column || is_blank(line) {
+                continue;
+            }
+
+            // The first key of a list item is on the same line as the dash
+            let key_column = match line[indentation(line)..].starts_with('-') {
+                true => indentation(line) + item_key_offset(&line[indentation(line)..]),
+                false => indentation(line),
+            };
+
+            if key_column == column
+                && line[column..].starts_with(key)
+                && line[column + key.len()..].trim_start().starts_with(':')
+            {
+                return Some(Location {
+                    line: index + 1,
+                    column: column + 1,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// The number of leading spaces
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// The offset of the first key in a list item line like `-   name: x`
+fn item_key_offset(item: &str) -> usize {
+    1 + item[1..].len() - item[1..].trim_start_matches(' ').len()
+}
+
+/// Lines without content or only with a comment
+fn is_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(line: usize, column: usize) -> Option<Location> {
+        Some(Location { line, column })
+    }
+
+    const CONFIG: &str = "\
+port: 9000
+# The targets
+targets:
+  - name: disk
+    regex: (?<result>\\d+)
+
+  -   name: load
+      commands:
+        - exec: cat /proc/loadavg
+          regex: nested
+      regex: (?<result>\\S+)
+host: localhost
+";
+
+    #[test]
+    fn locate_top_level_keys() {
+        let source_map = SourceMap::new(CONFIG);
+        assert_eq!(source_map.locate_key("port"), location(1, 1));
+        assert_eq!(source_map.locate_key("host"), location(12, 1));
+        // Keys that are only nested are not top level keys
+        assert_eq!(source_map.locate_key("regex"), None);
+    }
+
+    #[test]
+    fn locate_target_keys() {
+        let source_map = SourceMap::new(CONFIG);
+        assert_eq!(source_map.locate_target(0, None), location(4, 3));
+        assert_eq!(source_map.locate_target(0, Some("name")), location(4, 5));
+        assert_eq!(source_map.locate_target(0, Some("regex")), location(5, 5));
+        assert_eq!(source_map.locate_target(1, Some("name")), location(7, 7));
+        // The key of the command at a deeper level is skipped
+        assert_eq!(source_map.locate_target(1, Some("regex")), location(11, 7));
+        // Missing keys fall back to the start of the target
+        assert_eq!(
+            source_map.locate_target(0, Some("commands")),
+            location(4, 3)
+        );
+        assert_eq!(source_map.locate_target(2, None), None);
+    }
+
+    #[test]
+    fn locate_flow_style_is_not_supported() {
+        let source_map = SourceMap::new("targets: [{name: disk, regex: x}]\n");
+        assert_eq!(source_map.locate_target(0, Some("regex")), None);
+
+        // A flow style item is found, but not its keys
+        let source_map = SourceMap::new("targets:\n  - {name: disk, regex: x}\n");
+        assert_eq!(source_map.locate_target(0, Some("regex")), location(2, 3));
+    }
+
+    #[test]
+    fn locate_merged_anchors_is_not_supported() {
+        let source_map = SourceMap::new(
+            "defaults: &defaults\n  run_every: 5s\ntargets:\n  - <<: *defaults\n    name: disk\n",
+        );
+        assert_eq!(source_map.locate_target(0, Some("name")), location(5, 5));
+        // The key is only in the anchor, so the error points at the target
+        assert_eq!(
+            source_map.locate_target(0, Some("run_every")),
+            location(4, 3)
+        );
+    }
+
+    #[test]
+    fn locate_multiple_documents_uses_first_match() {
+        let source_map = SourceMap::new("---\nport: 1\n---\nport: 2\ntargets:\n  - name: disk\n");
+        assert_eq!(source_map.locate_key("port"), location(2, 1));
+        assert_eq!(source_map.locate_target(0, Some("name")), location(6, 5));
+    }
+}
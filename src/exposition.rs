@@ -0,0 +1,503 @@
+use crate::config::schema::TargetCommand;
+use crate::prometheus::ParsedResult;
+use log::warn;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+/// The suffixes of the samples that belong to a metric family of any type, e.g. `_bucket` of a histogram
+const SAMPLE_SUFFIXES: &[&str] = &[
+    "_total", "_created", "_bucket", "_count", "_sum", "_gcount", "_gsum", "_info",
+];
+
+/// The metric types of the OpenMetrics specification
+const METRIC_TYPES: &[&str] = &[
+    "counter",
+    "gauge",
+    "histogram",
+    "gaugehistogram",
+    "stateset",
+    "info",
+    "summary",
+    "unknown",
+];
+
+/// A metric family that was parsed from the Prometheus text format or OpenMetrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFamily {
+    pub name: String,
+    pub metric_type: String,
+    /// The help text without its escape sequences
+    pub help: Option<String>,
+    pub unit: Option<String>,
+    pub samples: Vec<Sample>,
+}
+
+/// A single line with a value of a metric family
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// The name of the sample, including suffixes like `_total` or `_bucket`
+    pub name: String,
+    /// The labels with their unescaped values
+    pub labels: Vec<(String, String)>,
+    /// The value as it was written
+    pub value: String,
+}
+
+impl MetricFamily {
+    fn new(name: &str) -> Self {
+        MetricFamily {
+            name: name.to_owned(),
+            metric_type: "unknown".to_owned(),
+            help: None,
+            unit: None,
+            samples: vec![],
+        }
+    }
+
+    /// Checks if a `# HELP`, `# TYPE` or `# UNIT` line with this metric name belongs to the family
+    fn has_metadata_name(&self, name: &str) -> bool {
+        self.name == name
+            || (self.metric_type == "counter" && name.strip_suffix("_total") == Some(&self.name))
+    }
+
+    /// Checks if a sample with this name belongs to the family.
+    /// Only the suffixes that the type of the family defines are allowed.
+    fn has_sample_name(&self, name: &str) -> bool {
+        let suffixes: &[&str] = match self.metric_type.as_str() {
+            "counter" => &["_total", "_created"],
+            "histogram" => &["_bucket", "_count", "_sum", "_created"],
+            "gaugehistogram" => &["_bucket", "_gcount", "_gsum"],
+            "summary" => &["_count", "_sum", "_created"],
+            "info" => &["_info"],
+            _ => &[],
+        };
+        match name.strip_prefix(&self.name) {
+            Some("") => true,
+            Some(suffix) => suffixes.contains(&suffix),
+            None => false,
+        }
+    }
+
+    /// Checks if the family or its samples could have the same name as the metric `name`
+    pub fn clashes_with(&self, name: &str) -> bool {
+        let is_suffix = |longer: &str, shorter: &str| {
+            longer
+                .strip_prefix(shorter)
+                .is_some_and(|suffix| SAMPLE_SUFFIXES.contains(&suffix))
+        };
+        self.name == name || is_suffix(name, &self.name) || is_suffix(&self.name, name)
+    }
+
+    /// Checks if the other family has the same name but a different type, both cannot be served
+    pub fn conflicts_with(&self, other: &MetricFamily) -> bool {
+        self.name == other.name && self.metric_type != other.metric_type
+    }
+}
+
+/// Parses and validates the output of a command in the Prometheus text format or OpenMetrics.
+///
+/// The families are converted so that they can be served as OpenMetrics, e.g. counters
+/// are named without `_total`, while their samples always have it. Timestamps and exemplars
+/// are dropped, the samples get the time of the scrape.
+pub fn parse(text: &str) -> Result<Vec<MetricFamily>, String> {
+    let mut families: Vec<MetricFamily> = vec![];
+    let mut names = HashSet::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {message}", index + 1);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            let keyword = parts.next().unwrap_or_default();
+            if keyword == "EOF" {
+                break;
+            }
+            if !["HELP", "TYPE", "UNIT"].contains(&keyword) {
+                // Other comments are allowed in the Prometheus text format
+                continue;
+            }
+
+            let name = parts.next().unwrap_or_default();
+            let text = parts.next().unwrap_or_default().trim().to_owned();
+            if !is_metric_name(name) {
+                return Err(error(format!("Invalid metric name '{name}'")));
+            }
+
+            let family = match families.last_mut() {
+                Some(family) if family.has_metadata_name(name) && family.samples.is_empty() => {
+                    family
+                }
+                _ => {
+                    families.push(MetricFamily::new(name));
+                    families.last_mut().unwrap()
+                }
+            };
+
+            match keyword {
+                "HELP" => family.help = Some(unescape_help(&text)),
+                "UNIT" => family.unit = Some(text),
+                _ => {
+                    family.metric_type = match text.as_str() {
+                        "untyped" => "unknown".to_owned(),
+                        x if METRIC_TYPES.contains(&x) => text,
+                        _ => return Err(error(format!("Invalid metric type '{text}'"))),
+                    };
+                    if family.metric_type == "counter" {
+                        if let Some(name) = family.name.strip_suffix("_total") {
+                            family.name = name.to_owned();
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+
+        let mut sample = parse_sample(line).map_err(error)?;
+        let family = match families.last_mut() {
+            Some(family) if family.has_sample_name(&sample.name) => family,
+            _ => {
+                families.push(MetricFamily::new(&sample.name));
+                families.last_mut().unwrap()
+            }
+        };
+        // Counters without the suffix are allowed in the Prometheus text format
+        if family.metric_type == "counter" && sample.name == family.name {
+            sample.name += "_total";
+        }
+        family.samples.push(sample);
+    }
+
+    for family in &families {
+        if !names.insert(family.name.clone()) {
+            return Err(format!(
+                "The metric family '{}' is not in one group",
+                family.name
+            ));
+        }
+    }
+
+    Ok(families)
+}
+
+/// Parses the output of a command and adds the labels of the command to every sample
+pub fn parse_output(command: &TargetCommand, std_out: &str) -> Result<Vec<MetricFamily>, String> {
+    let mut families = parse(std_out)?;
+    let labels: Vec<(String, String)> = command
+        .labels
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    apply_labels(&mut families, &labels);
+    Ok(families)
+}
+
+/// Lists every sample of the families as a result, e.g. to print them
+pub fn results(families: &[MetricFamily]) -> Vec<ParsedResult> {
+    families
+        .iter()
+        .flat_map(|family| &family.samples)
+        .map(|sample| ParsedResult {
+            captures: vec![],
+            labels: sample.labels.clone(),
+            // The value was validated while parsing
+            values: vec![(
+                sample.name.clone(),
+                sample.value.parse().unwrap_or(f64::NAN),
+            )],
+            state: None,
+        })
+        .collect()
+}
+
+/// Adds the labels to every sample, existing labels with the same name are replaced
+pub fn apply_labels(families: &mut [MetricFamily], labels: &[(String, String)]) {
+    for sample in families.iter_mut().flat_map(|family| &mut family.samples) {
+        sample
+            .labels
+            .retain(|(name, _)| !labels.iter().any(|(label, _)| label == name));
+        sample.labels.extend(labels.iter().cloned());
+    }
+}
+
+/// Writes the families in the OpenMetrics format.
+/// Families with the same name are merged and only the first sample of every series is kept.
+pub fn encode<'a>(
+    writer: &mut impl Write,
+    families: impl IntoIterator<Item = &'a MetricFamily>,
+) -> std::fmt::Result {
+    let mut merged: BTreeMap<&str, (&MetricFamily, Vec<&Sample>)> = BTreeMap::new();
+    for family in families {
+        let entry = merged
+            .entry(family.name.as_str())
+            .or_insert_with(|| (family, vec![]));
+        // A family with the same name but a different type would make the output invalid,
+        // the commands that print it fail, so this only keeps a conflict of targets out
+        if entry.0.conflicts_with(family) {
+            warn!(
+                "The metric family '{}' with the type '{}' is not served, because it has the type '{}' in another target",
+                family.name, family.metric_type, entry.0.metric_type
+            );
+            continue;
+        }
+        entry.1.extend(&family.samples);
+    }
+
+    for (family, samples) in merged.values() {
+        if let Some(help) = &family.help {
+            writeln!(writer, "# HELP {} {}", family.name, escape(help))?;
+        }
+        writeln!(writer, "# TYPE {} {}", family.name, family.metric_type)?;
+        if let Some(unit) = &family.unit {
+            writeln!(writer, "# UNIT {} {unit}", family.name)?;
+        }
+
+        let mut series = HashSet::new();
+        for sample in samples {
+            if !series.insert((&sample.name, &sample.labels)) {
+                continue;
+            }
+
+            write!(writer, "{}", sample.name)?;
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+                    .collect();
+                write!(writer, "{{{}}}", labels.join(","))?;
+            }
+            writeln!(writer, " {}", sample.value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a line like `name{label="value"} 1.0 1700000000`
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or_else(|| format!("The sample '{line}' has no value"))?;
+    let name = &line[..name_end];
+    if !is_metric_name(name) {
+        return Err(format!("Invalid metric name '{name}'"));
+    }
+
+    let mut rest = &line[name_end..];
+    let mut labels = vec![];
+    if let Some(label_text) = rest.strip_prefix('{') {
+        rest = parse_labels(label_text, &mut labels)?;
+    }
+
+    // The value can be followed by a timestamp and an exemplar, which are dropped
+    let value = rest.split_whitespace().next().unwrap_or_default();
+    if value.parse::<f64>().is_err() {
+        return Err(format!("Invalid value '{value}' of the sample '{name}'"));
+    }
+
+    Ok(Sample {
+        name: name.to_owned(),
+        labels,
+        value: value.to_owned(),
+    })
+}
+
+/// Parses the labels after the opening brace into `labels` and returns the text after the closing brace
+fn parse_labels<'a>(
+    mut text: &'a str,
+    labels: &mut Vec<(String, String)>,
+) -> Result<&'a str, String> {
+    loop {
+        text = text.trim_start();
+        if let Some(rest) = text.strip_prefix('}') {
+            return Ok(rest);
+        }
+
+        let (name, rest) = text
+            .split_once('=')
+            .ok_or_else(|| "Labels must be written as name=\"value\"".to_string())?;
+        let name = name.trim();
+        if !is_label_name(name) {
+            return Err(format!("Invalid label name '{name}'"));
+        }
+
+        let quoted = rest
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| format!("The value of the label '{name}' must be quoted"))?;
+        let not_closed = || format!("The value of the label '{name}' is not closed");
+
+        let mut chars = quoted.char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((index, '"')) => break index,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err(not_closed()),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err(not_closed()),
+            }
+        };
+        labels.push((name.to_owned(), value));
+
+        text = quoted[end + 1..].trim_start();
+        if !text.starts_with('}') {
+            text = text
+                .strip_prefix(',')
+                .ok_or_else(|| format!("The label '{name}' is not followed by a comma"))?;
+        }
+    }
+}
+
+/// Replaces the escape sequences of a help text, which are the same in both formats,
+/// except that the Prometheus text format does not escape quotes
+fn unescape_help(text: &str) -> String {
+    let mut help = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('n')) => {
+                help.push('\n');
+                chars.next();
+            }
+            ('\\', Some(&next @ ('\\' | '"'))) => {
+                help.push(next);
+                chars.next();
+            }
+            _ => help.push(c),
+        }
+    }
+    help
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Escapes a label value or help text for OpenMetrics
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_text_format() {
+        let text = r#"
+# HELP backup_files_total Number of files in the backup
+# TYPE backup_files_total counter
+backup_files_total{repo="home"} 1027 1700000000000
+# A comment
+backup_size_bytes{repo="home",path="C:\\Backup \"old\""} 4.2e9
+"#;
+
+        let families = parse(text).unwrap();
+
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].name, "backup_files");
+        assert_eq!(families[0].metric_type, "counter");
+        assert_eq!(families[0].samples[0].name, "backup_files_total");
+        assert_eq!(families[0].samples[0].value, "1027");
+        assert_eq!(families[1].metric_type, "unknown");
+        assert_eq!(
+            families[1].samples[0].labels[1],
+            ("path".to_string(), r#"C:\Backup "old""#.to_string())
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse("backup_size_bytes abc").is_err());
+        assert!(parse("backup-size 1").is_err());
+        assert!(parse("backup_size{repo=home} 1").is_err());
+        assert!(parse(r#"backup_size{repo="home" path="/"} 1"#).is_err());
+        assert!(parse(r#"backup_size{repo="home",} 1"#).is_ok());
+        assert!(parse("# TYPE backup_size bytes").is_err());
+        assert!(parse("a 1\nb 1\na 2").is_err());
+    }
+
+    #[test]
+    fn parse_sample_suffixes_by_type() {
+        let names = |text: &str| -> Vec<String> {
+            parse(text)
+                .unwrap()
+                .into_iter()
+                .map(|family| family.name)
+                .collect()
+        };
+
+        assert_eq!(
+            names("# TYPE h histogram\nh_bucket{le=\"+Inf\"} 1\nh_sum 2\nh_count 1"),
+            ["h"]
+        );
+        assert_eq!(
+            names("# TYPE c counter\nc_total 1\nc_sum 1"),
+            ["c", "c_sum"]
+        );
+        assert_eq!(names("# TYPE g gauge\ng 1\ng_total 2"), ["g", "g_total"]);
+        assert_eq!(names("u 1\nu_count 2"), ["u", "u_count"]);
+    }
+
+    #[test]
+    fn encode_escapes_help() {
+        let families = parse("# HELP size Size of \"C:\\\\Backup\"\\nin bytes\nsize 1").unwrap();
+        assert_eq!(
+            families[0].help.as_deref(),
+            Some("Size of \"C:\\Backup\"\nin bytes")
+        );
+
+        let mut body = String::new();
+        encode(&mut body, &families).unwrap();
+
+        assert_eq!(
+            body.lines().next().unwrap(),
+            r#"# HELP size Size of \"C:\\Backup\"\nin bytes"#
+        );
+    }
+
+    #[test]
+    fn family_clashes_with_names() {
+        let family = MetricFamily::new("disk_up");
+        assert!(family.clashes_with("disk_up"));
+        assert!(family.clashes_with("disk_up_total"));
+        assert!(MetricFamily::new("disk_runs_total").clashes_with("disk_runs"));
+        assert!(!family.clashes_with("disk_upper"));
+        assert!(!family.clashes_with("disk"));
+    }
+
+    #[test]
+    fn encode_applies_labels() {
+        let mut families =
+            parse("# TYPE backups gauge\nbackups{host=\"a\",repo=\"x\"} 1\n# EOF\nignored 1")
+                .unwrap();
+        apply_labels(&mut families, &[("host".to_string(), "b".to_string())]);
+
+        let mut body = String::new();
+        encode(&mut body, &families).unwrap();
+
+        assert_eq!(
+            body,
+            "# TYPE backups gauge\nbackups{repo=\"x\",host=\"b\"} 1\n"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::config::schema::{OutputParser, Target, TargetCommand};
+use crate::prometheus::ParsedResult;
+use crate::value_format::{mapped_state, parse_target_value};
+use regex::Regex;
+use serde_json::Value;
+use serde_json_path::{JsonPath, NodeList};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// The JSONPath of the items if `json_items` is not set
+const DEFAULT_ITEMS_PATH: &str = "$";
+
+/// Matches the JSONPath placeholders like `{$.name}` in label values
+pub static PLACEHOLDER_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(\$[^}]*)\}").unwrap());
+
+/// The compiled JSONPaths of a target, so they are not parsed again for every item
+#[derive(Default)]
+pub struct JsonPaths {
+    paths: HashMap<String, JsonPath>,
+}
+
+impl JsonPaths {
+    /// Compiles the `json_items`, the values and the label placeholders of the target.
+    /// Invalid paths are left out, they are reported when they are used.
+    pub fn new(target: &Target) -> Self {
+        if target.parser != OutputParser::Json {
+            return Default::default();
+        }
+
+        let mut paths = vec![target.json_items.as_deref().unwrap_or(DEFAULT_ITEMS_PATH)];
+        paths.extend(target.value_selectors());
+        for command in &target.commands {
+            for template in command.labels.values() {
+                paths.extend(
+                    PLACEHOLDER_REGEX
+                        .captures_iter(template)
+                        .map(|placeholder| placeholder.get(1).unwrap().as_str()),
+                );
+            }
+        }
+
+        JsonPaths {
+            paths: paths
+                .into_iter()
+                .filter_map(|path| Some((path.to_owned(), JsonPath::parse(path).ok()?)))
+                .collect(),
+        }
+    }
+
+    /// Applies the JSONPath to the value, paths that are not compiled yet are parsed first
+    fn query<'a>(&self, path: &str, value: &'a Value) -> Result<NodeList<'a>, String> {
+        match self.paths.get(path) {
+            Some(json_path) => Ok(json_path.query(value)),
+            None => Ok(parse_path(path)?.query(value)),
+        }
+    }
+}
+
+/// Parses the stdout of a command as JSON.
+///
+/// Every item that is selected by `json_items` creates a result, items that are arrays are
+/// expanded to one result per element. The result, the `value_groups` and the placeholders
+/// in the labels of the command are JSONPaths relative to the item.
+pub fn parse_output(
+    target: &Target,
+    command: &TargetCommand,
+    paths: &JsonPaths,
+    std_out: &str,
+) -> Result<Vec<ParsedResult>, String> {
+    let document: Value = match serde_json::from_str(std_out) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Could not parse stdout as JSON: {e}")),
+    };
+
+    let items_path = target.json_items.as_deref().unwrap_or(DEFAULT_ITEMS_PATH);
+    let mut items = vec![];
+    for node in paths.query(items_path, &document)?.all() {
+        match node {
+            Value::Array(elements) => items.extend(elements),
+            _ => items.push(node),
+        }
+    }
+
+    if items.is_empty() {
+        return Err("The JSONPath of 'json_items' did not select any items".to_string());
+    }
+
+    items
+        .into_iter()
+        .map(|item| parse_item(target, command, paths, item))
+        .collect()
+}
+
+/// Parses a JSONPath and formats the error for the logs
+pub fn parse_path(path: &str) -> Result<JsonPath, String> {
+    match JsonPath::parse(path) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Invalid JSONPath '{path}': {e}")),
+    }
+}
+
+/// Templates the labels of the command and parses the values of a single item
+fn parse_item(
+    target: &Target,
+    command: &TargetCommand,
+    paths: &JsonPaths,
+    item: &Value,
+) -> Result<ParsedResult, String> {
+    let mut labels = vec![];
+    for (label, template) in &command.labels {
+        let mut templated_value = String::new();
+        let mut last_end = 0;
+        for placeholder in PLACEHOLDER_REGEX.captures_iter(template) {
+            let whole = placeholder.get(0).unwrap();
+            templated_value.push_str(&template[last_end..whole.start()]);
+            if let Some(selected) = select(paths, &placeholder[1], item)? {
+                templated_value.push_str(&label_value(selected));
+            }
+            last_end = whole.end();
+        }
+        templated_value.push_str(&template[last_end..]);
+        labels.push((label.to_owned(), templated_value));
+    }
+
+    let mut captures = vec![];
+    let mut values = vec![];
+    let mut state = None;
+    for path in target.value_selectors() {
+        let selected = match select(paths, path, item)? {
+            Some(x) => x,
+            None => {
+                return Err(format!(
+                    "The JSONPath '{path}' did not select a value in {item}"
+                ))
+            }
+        };
+        captures.push((path.to_owned(), selected.to_string()));
+        if let (Some(result_path), Value::String(string)) = (target.result_selector(), selected) {
+            if path == result_path {
+                state = mapped_state(target, string);
+            }
+        }
+
+        // Only strings are written in the value format of the target
+        let value = match selected {
+            Value::Number(number) => number.as_f64().ok_or_else(|| number.to_string()),
+            Value::String(string) => parse_target_value(target, command, string),
+            Value::Bool(boolean) => Ok(if *boolean { 1.0 } else { 0.0 }),
+            _ => Err(format!("{selected} is not a number")),
+        };
+        match value {
+            Ok(value) => values.push((path.to_owned(), value)),
+            Err(e) => return Err(format!("Could not parse the value of '{path}' to f64: {e}")),
+        }
+    }
+
+    Ok(ParsedResult {
+        captures,
+        labels,
+        values,
+        state,
+    })
+}
+
+/// Selects at most one value in the item
+fn select<'a>(paths: &JsonPaths, path: &str, item: &'a Value) -> Result<Option<&'a Value>, String> {
+    match paths.query(path, item)?.at_most_one() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(format!(
+            "The JSONPath '{path}' selected more than one value"
+        )),
+    }
+}
+
+/// Strings are used without their quotes, everything else as JSON
+fn label_value(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        Value::Null => String::new(),
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::schema::OutputParser;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_output_expands_arrays() {
+        let target = Target {
+            parser: OutputParser::Json,
+            json_items: Some("$.blockdevices".to_string()),
+            json_value: Some("$.size".to_string()),
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("device".to_string(), "/dev/{$.name}".to_string())]),
+            ..Default::default()
+        };
+        let std_out =
+            r#"{"blockdevices": [{"name": "sda", "size": 512}, {"name": "sdb", "size": "1024"}]}"#;
+
+        let results = parse_output(&target, &command, &JsonPaths::new(&target), std_out).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0].labels,
+            vec![("device".to_string(), "/dev/sda".to_string())]
+        );
+        assert_eq!(results[0].values, vec![("$.size".to_string(), 512.0)]);
+        assert_eq!(results[1].values, vec![("$.size".to_string(), 1024.0)]);
+    }
+
+    #[test]
+    fn parse_output_missing_value() {
+        let target = Target {
+            parser: OutputParser::Json,
+            json_value: Some("$.total_size".to_string()),
+            ..Default::default()
+        };
+
+        let paths = JsonPaths::new(&target);
+        let std_out = r#"{"size": 1}"#;
+        assert!(parse_output(&target, &TargetCommand::default(), &paths, std_out).is_err());
+    }
+}
@@ -1,85 +1,112 @@
 mod cli;
 mod config;
+mod exposition;
+mod json_parser;
 mod prometheus;
+mod runner;
+mod scheduler;
 mod shell_commands;
-
-use crate::cli::CliArgs;
-use crate::config::read_cfg;
-use crate::config::schema::{Schema, Target, TargetCommand};
-use crate::prometheus::TargetMetrics;
-use crate::shell_commands::ShellCommand;
+mod subcommands;
+mod supervisor;
+#[cfg(test)]
+mod test_utils;
+mod transform;
+mod value_format;
+
+use crate::cli::{CliArgs, Command};
+use crate::config::schema::Schema;
+use crate::config::{find_cfg_path, read_cfg};
+use crate::prometheus::{encode_metrics, TargetMetrics};
+use crate::supervisor::{ReloadRequest, ReloadSender, Supervisor};
 use actix_web::middleware::Compress;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpResponse, HttpServer, Result};
-use clap::builder::Str;
 use clap::Parser;
-use log::{info, trace, warn};
-use prometheus_client::encoding::text::encode;
-use prometheus_client::metrics::family::Family;
+use log::{error, info, trace, LevelFilter};
 use prometheus_client::registry::Registry;
-use regex::Regex;
 use simple_logger::SimpleLogger;
-use std::collections::HashMap;
+use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::Duration;
 use std::{io, thread};
 use tokio::runtime;
+use tokio::sync::{mpsc, oneshot};
 
 // Taken from the Prometheus sample code
 pub struct AppState {
     pub registry: Registry,
+    /// The metrics of the targets whose commands print metrics that are served as they are
+    /// or that export a state set, see `serves_families`
+    pub exposition_targets: Vec<Arc<TargetMetrics>>,
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
+    let cli_args = CliArgs::parse();
+
+    // The tools print their own report, which already contains the problems
+    let log_level = match cli_args.command {
+        None => LevelFilter::Trace,
+        Some(_) => LevelFilter::Error,
+    };
+
     // Init simple log
-    SimpleLogger::new().init().unwrap();
+    SimpleLogger::new().with_level(log_level).init().unwrap();
 
-    let cli_args = CliArgs::parse();
+    if let Some(command) = &cli_args.command {
+        let exit_code = match command {
+            Command::CheckConfig => subcommands::check_config(&cli_args),
+            Command::RunOnce { target } => {
+                subcommands::run_once(&cli_args, target.as_deref()).await
+            }
+            Command::TestRegex {
+                target,
+                input,
+                regex,
+                regex_named_group,
+            } => subcommands::test_regex(
+                &cli_args,
+                target,
+                input.as_deref(),
+                regex.clone(),
+                regex_named_group.clone(),
+            ),
+        };
+        process::exit(exit_code);
+    }
 
     // Read the config
-    let config: Arc<Schema> = match read_cfg(&cli_args) {
-        Ok(s) => Arc::new(s),
-        Err(x) => panic!("Could not read config: {x}"),
+    let config_path = match find_cfg_path(&cli_args) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Could not read config: {x}");
+            process::exit(1);
+        }
+    };
+    let config: Schema = match read_cfg(&config_path, &cli_args) {
+        Ok(s) => s,
+        Err(x) => {
+            error!("Could not read config: {x}");
+            process::exit(1);
+        }
     };
     trace!("Parsed config data: {config:?}");
 
-    let mut state = AppState {
+    let state = Arc::new(Mutex::new(AppState {
         registry: Registry::default(),
-    };
-
-    let mut metrics = vec![Arc::new(TargetMetrics::default()); config.targets.len()];
-
-    for (i, target) in config.targets.iter().enumerate() {
-        let target_metrics = Arc::new(TargetMetrics {
-            last_result: Family::default(),
-            last_duration: Family::default(),
-        });
-
-        state.registry.register(
-            format!("{}_result", target.name),
-            "The last parsed result of a command target command",
-            target_metrics.last_result.clone(),
-        );
-
-        state.registry.register(
-            format!("{}_duration", target.name),
-            "Number of milliseconds the last command execution took",
-            target_metrics.last_duration.clone(),
-        );
-
-        metrics[i] = target_metrics;
+        exposition_targets: vec![],
+    }));
+    let state_data = Data::from(state.clone());
 
-        info!("Created metrics for target: {}", target.name);
-    }
+    let (reload_sender, reload_requests) = mpsc::unbounded_channel();
+    let reload_data = Data::new(reload_sender.clone());
 
-    let state = Mutex::new(state);
-    let state_data = Data::new(state);
+    let host = config.host.clone();
+    let port = config.port;
+    let supervisor = Supervisor::new(config_path, cli_args, config, state);
 
-    info!("Starting Web Server on {}:{}", config.host, config.port);
+    info!("Starting Web Server on {host}:{port}");
 
-    match start_tasks_worker(metrics, config.clone()) {
+    match start_tasks_worker(supervisor, reload_sender, reload_requests) {
         Ok(_) => info!("Started background thread"),
         Err(x) => panic!("Could not start background thread {x}"),
     }
@@ -88,9 +115,11 @@ async fn main() -> io::Result<()> {
         App::new()
             .wrap(Compress::default())
             .app_data(state_data.clone())
+            .app_data(reload_data.clone())
             .service(web::resource("/metrics").route(web::get().to(metrics_handler)))
+            .service(web::resource("/-/reload").route(web::post().to(reload_handler)))
     })
-    .bind((config.host.to_owned(), config.port))?
+    .bind((host, port))?
     .run()
     .await
 }
@@ -99,17 +128,42 @@ async fn main() -> io::Result<()> {
 pub async fn metrics_handler(state: Data<Mutex<AppState>>) -> Result<HttpResponse> {
     let state = state.lock().unwrap();
     let mut body = String::new();
-    encode(&mut body, &state.registry).unwrap();
+    encode_metrics(&mut body, &state.registry, &state.exposition_targets).unwrap();
     Ok(HttpResponse::Ok()
         .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
         .body(body))
 }
 
+/// This method is called when a POST request to /-/reload is received. Reloads the config file
+/// and responds with an error if the new config could not be applied.
+pub async fn reload_handler(reload_sender: Data<ReloadSender>) -> Result<HttpResponse> {
+    let (reply, result) = oneshot::channel();
+    if reload_sender
+        .send(ReloadRequest { reply: Some(reply) })
+        .is_err()
+    {
+        return Ok(HttpResponse::ServiceUnavailable().body("The background thread is not running"));
+    }
+
+    match result.await {
+        Ok(Ok(_)) => Ok(HttpResponse::Ok().body("Reloaded the config file")),
+        Ok(Err(e)) => Ok(HttpResponse::InternalServerError()
+            .body(format!("Could not reload the config file: {e}"))),
+        Err(_) => {
+            Ok(HttpResponse::ServiceUnavailable().body("The background thread is not running"))
+        }
+    }
+}
+
 /// Starts the thread that handles the command targets that are defined in the config file
-fn start_tasks_worker(state: Vec<Arc<TargetMetrics>>, config: Arc<Schema>) -> io::Result<()> {
+fn start_tasks_worker(
+    supervisor: Supervisor,
+    reload_sender: ReloadSender,
+    reload_requests: mpsc::UnboundedReceiver<ReloadRequest>,
+) -> io::Result<()> {
     match thread::Builder::new()
         .name("Target Runner Background task".to_string())
-        .spawn(move || tasks_worker(state, config.clone()))
+        .spawn(move || tasks_worker(supervisor, reload_sender, reload_requests))
     {
         Err(x) => Err(x),
         Ok(_) => Ok(()),
@@ -120,110 +174,16 @@ fn start_tasks_worker(state: Vec<Arc<TargetMetrics>>, config: Arc<Schema>) -> io
 ///
 /// The goal is to run the commands in a "ThreadPool" thread indefinitely with
 /// the interval between executions that is specified in the config file
-fn tasks_worker(state: Vec<Arc<TargetMetrics>>, config: Arc<Schema>) {
+fn tasks_worker(
+    supervisor: Supervisor,
+    reload_sender: ReloadSender,
+    reload_requests: mpsc::UnboundedReceiver<ReloadRequest>,
+) {
     let threaded_rt = runtime::Builder::new_multi_thread()
         .enable_io()
+        .enable_time()
         .build()
         .unwrap();
 
-    // Create a map of the indices
-    let mut timers: HashMap<usize, Duration> = Default::default();
-
-    for (index, target) in config.targets.iter().enumerate() {
-        timers.insert(index, target.run_every.into());
-    }
-
-    // 🤡
-    let config_deref = config.clone();
-    loop {
-        // Find the next timer to wait for
-        let next_timer = { timers.iter().min_by_key(|&(_, &duration)| duration) };
-
-        let (index, duration) = match next_timer {
-            Some((index, duration)) => (*index, duration),
-            None => continue,
-        };
-
-        let target: Target = config_deref.targets[index].clone();
-        let metrics: Arc<TargetMetrics> = state[index].clone();
-
-        trace!("Waiting for next target '{}'", target.name);
-        // Sleep for the duration
-        sleep(*duration);
-        trace!("Finished waiting for target '{}'", target.name);
-
-        // Clone the state variables so that they can be moved into the "ThreadPool" thread
-        let thread_target = target.clone();
-        threaded_rt.spawn(async move {
-            match handle_target(metrics, &thread_target).await {
-                Ok(_) => trace!("Command executed successfully"),
-                Err(e) => warn!("Command execution failed {e}"),
-            };
-        });
-
-        // Deduct the time slept from all durations and set the duration of the timer we waited for
-        // back to its config value
-        let duration_copy = *duration;
-        {
-            let timer_values_mut = timers.values_mut();
-
-            for value in timer_values_mut {
-                *value -= duration_copy;
-            }
-        }
-
-        let target_interval: Duration = target.run_every.clone().into();
-        let current_duration = timers.get_mut(&index).unwrap();
-        *current_duration = target_interval;
-        trace!(
-            "Reset target '{}' to duration {}",
-            target.name,
-            target.run_every
-        );
-    }
-}
-
-/// This method is called for each command target when the timer ticks
-/// The command is executed and the output interpreted
-async fn handle_target(state: Arc<TargetMetrics>, target: &Target) -> Result<(), String> {
-    let regex = match Regex::new(&format!(r"(?m){}", &*target.regex)) {
-        Ok(x) => x,
-        Err(err) => return Err(err.to_string()),
-    };
-
-    info!("Handling target '{}'", target.name);
-
-    for command in target.commands.iter() {
-        match handle_target_command(state.clone(), &regex, target, command).await {
-            Ok(_) => (),
-            Err(e) => {
-                warn!(
-                    "Target: '{}' Error handling command '{}' {}",
-                    target.name, command.exec, e
-                );
-                return Err(e);
-            }
-        }
-    }
-
-    Ok(())
-}
-
-async fn handle_target_command(
-    state: Arc<TargetMetrics>,
-    regex: &Regex,
-    target: &Target,
-    command: &TargetCommand,
-) -> Result<(), String> {
-    let cmd = ShellCommand::new(&*command.exec, "");
-
-    let execution_result = cmd.execute().await;
-
-    match execution_result.0 {
-        Ok(x) => match state.update_result(&target, &command, &regex, &x, &execution_result.1) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Error updating result: {}", e)),
-        },
-        Err(_) => Err("Error executing command".to_string()),
-    }
+    threaded_rt.block_on(supervisor.run(reload_sender, reload_requests));
 }
@@ -1,81 +1,1443 @@
-use crate::config::schema::{Target, TargetCommand};
-use prometheus_client::metrics::family::Family;
+use crate::config::schema::{
+    MatchMode, MetricType, OutputParser, StaleAfter, StaleEvent, Target, TargetCommand,
+};
+use crate::exposition::{MetricFamily, Sample};
+use crate::json_parser::JsonPaths;
+use crate::transform::{transform_results, Transform};
+use crate::value_format::{mapped_state, parse_target_value};
+use crate::{exposition, json_parser};
+use log::warn;
+use prometheus_client::encoding::text::{encode_eof, encode_registry};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
 use prometheus_client::metrics::gauge::Gauge;
-use regex::Regex;
-use std::process::Output;
-use std::sync::atomic::AtomicU64;
-use std::time::Duration;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use regex::{Captures, Regex};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The last update of every series of the parsed values by its labels
+type SeriesUpdates = HashMap<Vec<(String, String)>, SeriesUpdate>;
+
+/// The last parsed value of several series
+type LastValues = HashMap<Vec<(String, String)>, f64>;
+
+/// A family of gauges with floating point values
+pub type FloatGaugeFamily = Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>;
+
+/// A family of counters with floating point values
+pub type FloatCounterFamily = Family<Vec<(String, String)>, Counter<f64, AtomicU64>>;
+
+/// A family of histograms whose buckets are set by the constructor
+pub type BucketHistogramFamily = Family<Vec<(String, String)>, Histogram, Buckets>;
+
+/// The buckets of histograms whose target does not set any, like the Prometheus client libraries use
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The names of the metrics every target registers, without the target name prefix
+pub const TARGET_METRIC_NAMES: &[&str] = &[
+    "result",
+    "duration",
+    "runs",
+    "skipped_runs",
+    "queue_wait_seconds",
+    "duration_seconds",
+    "up",
+    "last_run_timestamp_seconds",
+    "last_success_timestamp_seconds",
+    "exit_code",
+    "terminated_by_signal",
+    "state",
+];
+
+/// The names of the metrics the target registers, including the target name prefix
+pub fn registered_metric_names(target: &Target) -> impl Iterator<Item = String> + '_ {
+    TARGET_METRIC_NAMES
+        .iter()
+        .copied()
+        .chain(target.value_groups.values().map(|group| group.metric()))
+        .map(|name| format!("{}_{name}", target.name))
+}
+
+/// The outcome of a single command execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The command exited with a success exit code and its output could be parsed
+    Success,
+    /// The command exited with an exit code that is not in `success_exit_codes`
+    Failure,
+    /// The command succeeded, but its output could not be parsed
+    ParseError,
+    /// The command could not be started
+    SpawnError,
+    /// The command was killed, because it did not exit within its timeout
+    Timeout,
+}
+
+impl RunOutcome {
+    /// The value of the `outcome` label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunOutcome::Success => "success",
+            RunOutcome::Failure => "failure",
+            RunOutcome::ParseError => "parse_error",
+            RunOutcome::SpawnError => "spawn_error",
+            RunOutcome::Timeout => "timeout",
+        }
+    }
+}
+
+/// The values that were extracted from the output of a command
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedResult {
+    /// The named groups of the RegEx that matched or the values the JSONPaths selected
+    pub captures: Vec<(String, String)>,
+    /// The labels of the series that was updated
+    pub labels: Vec<(String, String)>,
+    /// The parsed values of the result and the `value_groups` by group name or JSONPath
+    pub values: Vec<(String, f64)>,
+    /// The word of the `value_map` the result was mapped from
+    pub state: Option<String>,
+}
+
+/// Creates the histograms of a family with the upper bounds of its buckets
+#[derive(Debug, Clone)]
+pub struct Buckets(Vec<f64>);
+
+impl Buckets {
+    pub fn new(upper_bounds: &[f64]) -> Self {
+        Buckets(upper_bounds.to_vec())
+    }
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Buckets::new(DEFAULT_BUCKETS)
+    }
+}
+
+impl MetricConstructor<Histogram> for Buckets {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.0.iter().copied())
+    }
+}
+
+/// The metrics of a parsed value, depending on the `metric_type` of the target
+#[derive(Debug, Clone)]
+pub enum ValueMetric {
+    Gauge(FloatGaugeFamily),
+    /// The counters and the last parsed value of every series to calculate the increase
+    Counter(FloatCounterFamily, Arc<Mutex<LastValues>>),
+    Histogram(BucketHistogramFamily),
+}
+
+impl Default for ValueMetric {
+    fn default() -> Self {
+        ValueMetric::Gauge(Default::default())
+    }
+}
+
+impl ValueMetric {
+    /// Creates the metric with the `metric_type` and `buckets` of the target
+    pub fn new(target: &Target) -> Self {
+        match target.metric_type {
+            MetricType::Gauge => ValueMetric::Gauge(Default::default()),
+            MetricType::Counter => ValueMetric::Counter(Default::default(), Default::default()),
+            MetricType::Histogram => {
+                let buckets = match &target.buckets {
+                    Some(upper_bounds) => Buckets::new(upper_bounds),
+                    None => Buckets::default(),
+                };
+                ValueMetric::Histogram(Family::new_with_constructor(buckets))
+            }
+        }
+    }
+
+    /// Registers the metric. The name of counters must not end with `_total`,
+    /// because prometheus-client adds it.
+    fn register(&self, registry: &mut Registry, name: &str, help: &str) {
+        match self {
+            ValueMetric::Gauge(family) => registry.register(name, help, family.clone()),
+            ValueMetric::Counter(family, _) => registry.register(
+                name.strip_suffix("_total").unwrap_or(name),
+                help,
+                family.clone(),
+            ),
+            ValueMetric::Histogram(family) => registry.register(name, help, family.clone()),
+        }
+    }
+
+    /// Checks that the metric can be updated with the value
+    fn check(&self, value: f64) -> Result<(), String> {
+        match self {
+            ValueMetric::Counter(..) if value.is_nan() || value < 0.0 => {
+                Err(format!("A counter can not be set to {value}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the gauge, increases the counter by the increase since the last value
+    /// or observes the value with the histogram. The value has to pass [ValueMetric::check].
+    fn update(&self, labels: &Vec<(String, String)>, value: f64) {
+        match self {
+            ValueMetric::Gauge(family) => {
+                family.get_or_create(labels).set(value);
+            }
+            ValueMetric::Counter(family, last_values) => {
+                let last_value = last_values.lock().unwrap().insert(labels.clone(), value);
+                // A smaller value than before means that the counting restarted at zero
+                let increase = match last_value {
+                    Some(last_value) if value >= last_value => value - last_value,
+                    _ => value,
+                };
+                family.get_or_create(labels).inc_by(increase);
+            }
+            ValueMetric::Histogram(family) => family.get_or_create(labels).observe(value),
+        }
+    }
+
+    fn remove(&self, labels: &Vec<(String, String)>) {
+        match self {
+            ValueMetric::Gauge(family) => {
+                family.remove(labels);
+            }
+            ValueMetric::Counter(family, last_values) => {
+                family.remove(labels);
+                last_values.lock().unwrap().remove(labels);
+            }
+            ValueMetric::Histogram(family) => {
+                family.remove(labels);
+            }
+        }
+    }
+}
+
+/// The command that updated a series last and the run of the target it did that in
+#[derive(Debug, Clone, Copy)]
+struct SeriesUpdate {
+    command_index: usize,
+    generation: u64,
+}
 
-#[derive(Default)]
 pub struct TargetMetrics {
-    pub last_result: Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>,
+    /// The metric of the result, its type is the `metric_type` of the target
+    pub last_result: ValueMetric,
     pub last_duration: Family<Vec<(String, String)>, Gauge>,
+    pub duration_seconds: BucketHistogramFamily,
+    pub runs_total: Family<Vec<(String, String)>, Counter>,
+    pub skipped_runs_total: Family<Vec<(String, String)>, Counter>,
+    pub queue_wait_seconds: BucketHistogramFamily,
+    /// Whether the last execution of a command succeeded, by command
+    pub up: Family<Vec<(String, String)>, Gauge>,
+    pub last_run_timestamp_seconds: FloatGaugeFamily,
+    pub last_success_timestamp_seconds: FloatGaugeFamily,
+    /// The exit code of the last execution of a command, by command
+    pub exit_code: Family<Vec<(String, String)>, Gauge>,
+    /// The signal that terminated the last execution of a command, by command
+    pub terminated_by_signal: Family<Vec<(String, String)>, Gauge>,
+    /// The metrics of the `value_groups` of the target by group name
+    pub value_groups: HashMap<String, ValueMetric>,
+    /// One series per word of the `value_map` that is set for the current word.
+    /// Only used if the target exports a state set.
+    state: Mutex<BTreeMap<Vec<(String, String)>, bool>>,
+    /// The name of the state set, if the target exports one
+    state_name: Option<String>,
+    /// The number of the current run of the target
+    generation: AtomicU64,
+    /// The last update of every series of the parsed values
+    series: Mutex<SeriesUpdates>,
+    /// The number of the run that last recorded the per command metrics, by command index
+    command_updates: Mutex<HashMap<usize, u64>>,
+    /// The metric families every command printed in its last run and the number of that
+    /// run, by command index. Only used by targets with the prometheus parser.
+    exposition: Mutex<BTreeMap<usize, (u64, Vec<MetricFamily>)>>,
+    /// The compiled JSONPaths of the target. Only used by targets with the json parser.
+    json_paths: JsonPaths,
+    /// The compiled `transform` of the target
+    transform: Transform,
+    /// The names of the other metrics in the registry, which the metric families of the
+    /// commands must not use. Only used by targets with the prometheus parser.
+    reserved_names: Mutex<HashSet<String>>,
+}
+
+impl Default for TargetMetrics {
+    fn default() -> Self {
+        TargetMetrics {
+            last_result: Default::default(),
+            last_duration: Default::default(),
+            duration_seconds: Family::new_with_constructor(Buckets::default()),
+            runs_total: Default::default(),
+            skipped_runs_total: Default::default(),
+            // 5ms up to ~10s
+            queue_wait_seconds: Family::new_with_constructor(Buckets(
+                exponential_buckets(0.005, 2.0, 12).collect(),
+            )),
+            up: Default::default(),
+            last_run_timestamp_seconds: Default::default(),
+            last_success_timestamp_seconds: Default::default(),
+            exit_code: Default::default(),
+            terminated_by_signal: Default::default(),
+            value_groups: Default::default(),
+            state: Default::default(),
+            state_name: None,
+            generation: Default::default(),
+            series: Default::default(),
+            command_updates: Default::default(),
+            exposition: Default::default(),
+            json_paths: Default::default(),
+            transform: Default::default(),
+            reserved_names: Default::default(),
+        }
+    }
 }
 
 impl TargetMetrics {
+    /// Creates the metrics of the result and the value groups with the metric type of the target
+    pub fn new(target: &Target) -> Self {
+        let duration_buckets = match &target.duration_buckets {
+            Some(upper_bounds) => Buckets::new(upper_bounds),
+            None => Buckets::default(),
+        };
+        TargetMetrics {
+            last_result: ValueMetric::new(target),
+            duration_seconds: Family::new_with_constructor(duration_buckets),
+            value_groups: target
+                .value_groups
+                .keys()
+                .map(|group| (group.clone(), ValueMetric::new(target)))
+                .collect(),
+            json_paths: JsonPaths::new(target),
+            transform: Transform::new(target),
+            state_name: target.state_set.then(|| format!("{}_state", target.name)),
+            ..Default::default()
+        }
+    }
+
+    /// Registers all metrics of a target in the registry.
+    /// The metric names are prefixed with the name of the target.
+    pub fn register(&self, registry: &mut Registry, target: &Target) {
+        let result_help = match target.metric_type {
+            MetricType::Gauge => "The last parsed result of a command target command",
+            MetricType::Counter => "The total that was parsed from the output of a command",
+            MetricType::Histogram => "The results that were parsed from the output of a command",
+        };
+        self.last_result
+            .register(registry, &format!("{}_result", target.name), result_help);
+
+        if target.legacy_duration_gauge {
+            registry.register(
+                format!("{}_duration", target.name),
+                "Number of milliseconds the last command execution took",
+                self.last_duration.clone(),
+            );
+        }
+
+        registry.register(
+            format!("{}_duration_seconds", target.name),
+            "Time the command executions took by outcome",
+            self.duration_seconds.clone(),
+        );
+
+        registry.register(
+            format!("{}_runs", target.name),
+            "Number of command executions by outcome",
+            self.runs_total.clone(),
+        );
+
+        registry.register(
+            format!("{}_skipped_runs", target.name),
+            "Number of runs that were skipped, because the previous run was still in progress",
+            self.skipped_runs_total.clone(),
+        );
+
+        registry.register(
+            format!("{}_queue_wait_seconds", target.name),
+            "Time commands waited for a free slot of the concurrency limits",
+            self.queue_wait_seconds.clone(),
+        );
+
+        registry.register(
+            format!("{}_up", target.name),
+            "Whether the last execution of a command was successful",
+            self.up.clone(),
+        );
+
+        registry.register(
+            format!("{}_last_run_timestamp_seconds", target.name),
+            "Timestamp of the last execution of a command",
+            self.last_run_timestamp_seconds.clone(),
+        );
+
+        registry.register(
+            format!("{}_last_success_timestamp_seconds", target.name),
+            "Timestamp of the last successful execution of a command",
+            self.last_success_timestamp_seconds.clone(),
+        );
+
+        registry.register(
+            format!("{}_exit_code", target.name),
+            "The exit code of the last execution of a command",
+            self.exit_code.clone(),
+        );
+
+        registry.register(
+            format!("{}_terminated_by_signal", target.name),
+            "The signal that terminated the last execution of a command, 0 if it exited",
+            self.terminated_by_signal.clone(),
+        );
+
+        for (group, value_group) in &target.value_groups {
+            let Some(metric) = self.value_groups.get(group) else {
+                continue;
+            };
+            let help = match (value_group.help(), target.metric_type) {
+                (Some(help), _) => help.to_owned(),
+                (None, MetricType::Gauge) => {
+                    format!("The last parsed value of '{group}' in the output of a command")
+                }
+                (None, _) => format!("The values of '{group}' in the output of a command"),
+            };
+            metric.register(
+                registry,
+                &format!("{}_{}", target.name, value_group.metric()),
+                &help,
+            );
+        }
+
+        // prometheus-client has no state set type, the state set is encoded with the
+        // metric families of the commands, see `families`
+    }
+
+    /// Counts a command execution with the given outcome
+    pub fn record_outcome(&self, target: &Target, outcome: RunOutcome) {
+        self.runs_total
+            .get_or_create(&vec![
+                ("name".to_owned(), target.name.to_owned()),
+                ("outcome".to_owned(), outcome.as_str().to_owned()),
+            ])
+            .inc();
+    }
+
+    /// Records whether a command execution succeeded and when it finished
+    pub fn record_run(&self, target: &Target, command_index: usize, outcome: RunOutcome) {
+        let labels = command_labels(target, command_index);
+        self.command_updated(command_index);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let success = outcome == RunOutcome::Success;
+        self.up.get_or_create(&labels).set(i64::from(success));
+        self.last_run_timestamp_seconds
+            .get_or_create(&labels)
+            .set(now);
+        if success {
+            self.last_success_timestamp_seconds
+                .get_or_create(&labels)
+                .set(now);
+        }
+    }
+
+    /// Marks a command as down that was not executed, because a previous command of the run failed
+    pub fn record_not_run(&self, target: &Target, command_index: usize) {
+        self.command_updated(command_index);
+        self.up
+            .get_or_create(&command_labels(target, command_index))
+            .set(0);
+    }
+
+    /// Records the exit code of a command execution or the signal that terminated it.
+    /// A command that was terminated by a signal has no exit code.
+    pub fn record_exit_status(&self, target: &Target, command_index: usize, status: &ExitStatus) {
+        let labels = command_labels(target, command_index);
+
+        match status.code() {
+            Some(code) => {
+                self.exit_code.get_or_create(&labels).set(code.into());
+            }
+            None => {
+                self.exit_code.remove(&labels);
+            }
+        }
+        self.terminated_by_signal
+            .get_or_create(&labels)
+            .set(termination_signal(status).unwrap_or_default().into());
+    }
+
+    /// Removes the exit code of a command execution that did not exit on its own.
+    /// A command that timed out was killed, the others were not started.
+    pub fn record_missing_exit_status(
+        &self,
+        target: &Target,
+        command_index: usize,
+        outcome: RunOutcome,
+    ) {
+        let labels = command_labels(target, command_index);
+
+        self.exit_code.remove(&labels);
+        match (outcome, TIMEOUT_SIGNAL) {
+            (RunOutcome::Timeout, Some(signal)) => {
+                self.terminated_by_signal
+                    .get_or_create(&labels)
+                    .set(signal.into());
+            }
+            _ => {
+                self.terminated_by_signal.remove(&labels);
+            }
+        }
+    }
+
+    /// Records how long a command execution with the given outcome took
+    pub fn record_duration(&self, target: &Target, outcome: RunOutcome, duration: &Duration) {
+        self.duration_seconds
+            .get_or_create(&vec![
+                ("name".to_owned(), target.name.to_owned()),
+                ("outcome".to_owned(), outcome.as_str().to_owned()),
+            ])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Counts a run that was skipped because of the overlap policy of the target
+    pub fn record_skipped(&self, target: &Target) {
+        self.skipped_runs_total
+            .get_or_create(&vec![("name".to_owned(), target.name.to_owned())])
+            .inc();
+    }
+
+    /// Records how long a command waited for the concurrency limits
+    pub fn record_queue_wait(&self, target: &Target, wait: &Duration) {
+        self.queue_wait_seconds
+            .get_or_create(&vec![("name".to_owned(), target.name.to_owned())])
+            .observe(wait.as_secs_f64());
+    }
+
     pub fn update_result(
         &self,
         target: &Target,
+        command_index: usize,
         command: &TargetCommand,
         regex: &Regex,
         execution_result: &Output,
         duration: &Duration,
-    ) -> Result<(), String> {
+    ) -> Result<Vec<ParsedResult>, String> {
         let std_out = String::from_utf8_lossy(&execution_result.stdout);
+        if target.parser == OutputParser::Prometheus {
+            return self.update_exposition(
+                target,
+                command_index,
+                command,
+                execution_result,
+                duration,
+            );
+        }
 
-        let captures = match regex.captures(std_out.trim()) {
-            Some(caps) => caps,
-            None => {
-                return Err("RegEx did not find any captures in stdout".to_string());
+        let mut results = parse_output(target, command, regex, &self.json_paths, &std_out)?;
+        transform_results(target, &self.transform, &mut results)?;
+
+        // Nothing is updated if one of the values is invalid, so no series is left without its series update
+        for parsed in &results {
+            for (group, value) in &parsed.values {
+                for metric in self.value_metrics(target, group) {
+                    metric.check(*value)?;
+                }
             }
-        };
+        }
 
-        let cap = captures
-            .name(&*target.regex_named_group)
-            .map_or("", |m| m.as_str());
+        for parsed in &mut results {
+            let mut result_labels = result_labels(target, &execution_result.status);
+            result_labels.append(&mut parsed.labels);
+            parsed.labels = result_labels;
 
-        let mut result_labels = vec![
-            ("name".to_owned(), target.name.to_owned()),
-            (
-                "exit_code".to_owned(),
-                execution_result.status.to_string().to_owned(),
-            ),
-        ];
+            for (group, value) in &parsed.values {
+                for metric in self.value_metrics(target, group) {
+                    metric.update(&parsed.labels, *value);
+                }
+            }
+
+            if target.state_set {
+                self.update_state(target, &parsed.labels, parsed.state.as_deref());
+            }
+
+            self.last_duration
+                .get_or_create(&parsed.labels)
+                .set(duration.as_millis() as i64);
+        }
+
+        let mut series = self.series.lock().unwrap();
+        let update = self.series_update(command_index);
+        for parsed in &results {
+            series.insert(parsed.labels.clone(), update);
+        }
+
+        // Every match or JSON item creates a series, so some may disappear between runs
+        if target.match_mode == MatchMode::All || target.parser == OutputParser::Json {
+            let current: HashSet<_> = results.iter().map(|parsed| &parsed.labels).collect();
+            self.remove_series(target, &mut series, |labels, update| {
+                update.command_index == command_index && !current.contains(labels)
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Keeps the metric families a command printed to serve them as they are
+    fn update_exposition(
+        &self,
+        target: &Target,
+        command_index: usize,
+        command: &TargetCommand,
+        execution_result: &Output,
+        duration: &Duration,
+    ) -> Result<Vec<ParsedResult>, String> {
+        let std_out = String::from_utf8_lossy(&execution_result.stdout);
+        let families = exposition::parse_output(command, &std_out)?;
+        {
+            let reserved_names = self.reserved_names.lock().unwrap();
+            let clash = families
+                .iter()
+                .find(|family| reserved_names.iter().any(|name| family.clashes_with(name)));
+            if let Some(family) = clash {
+                return Err(format!(
+                    "The metric family '{}' has the name of a metric of the exporter",
+                    family.name
+                ));
+            }
+        }
+        let results = exposition::results(&families);
+        let generation = self.generation.load(Ordering::Relaxed);
+        let mut exposition = self.exposition.lock().unwrap();
+        let conflict = exposition
+            .iter()
+            .filter(|(index, _)| **index != command_index)
+            .flat_map(|(_, (_, others))| others)
+            .find_map(|other| {
+                families
+                    .iter()
+                    .find(|family| family.conflicts_with(other))
+                    .map(|family| (family, other))
+            });
+        if let Some((family, other)) = conflict {
+            return Err(format!(
+                "The metric family '{}' has the type '{}', but another command printed it with the type '{}'",
+                family.name, family.metric_type, other.metric_type
+            ));
+        }
+        exposition.insert(command_index, (generation, families));
+        drop(exposition);
 
+        let mut duration_labels = result_labels(target, &execution_result.status);
         for (label, value) in &command.labels {
-            let mut templated_value = value.clone();
-            for group_name in regex
-                .capture_names()
-                .filter(|x| x.is_some())
-                .map(|x| x.unwrap())
-            {
-                let group_content = captures.name(group_name).map_or("", |m| m.as_str());
+            duration_labels.push((label.to_owned(), value.to_owned()));
+        }
+        self.last_duration
+            .get_or_create(&duration_labels)
+            .set(duration.as_millis() as i64);
+        self.series
+            .lock()
+            .unwrap()
+            .insert(duration_labels, self.series_update(command_index));
+
+        Ok(results)
+    }
+
+    /// The metrics that are updated with the parsed value of a group
+    fn value_metrics<'a>(
+        &'a self,
+        target: &Target,
+        group: &str,
+    ) -> impl Iterator<Item = &'a ValueMetric> {
+        let result = (Some(group) == target.result_selector()).then_some(&self.last_result);
+        result.into_iter().chain(self.value_groups.get(group))
+    }
+
+    /// Sets the names of the metrics that are registered besides the metric families the
+    /// commands print, the commands that print a family with one of these names fail
+    pub fn reserve_names(&self, names: HashSet<String>) {
+        *self.reserved_names.lock().unwrap() = names;
+    }
+
+    /// Sets the series of the current word to 1 and the series of all other words of the
+    /// `value_map` to 0. Results that were not mapped from a word set every series to 0.
+    fn update_state(&self, target: &Target, labels: &[(String, String)], state: Option<&str>) {
+        let mut states = self.state.lock().unwrap();
+        for word in target.value_map.keys() {
+            states.insert(
+                state_labels(target, labels, word),
+                Some(word.as_str()) == state,
+            );
+        }
+    }
+
+    /// The metric families that are served besides the registry, which are the families the
+    /// commands printed and the state set
+    fn families(&self) -> Vec<MetricFamily> {
+        let mut families: Vec<MetricFamily> = self
+            .exposition
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|(_, families)| families.iter().cloned())
+            .collect();
+
+        if let Some(name) = &self.state_name {
+            let samples = self
+                .state
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(labels, is_set)| Sample {
+                    name: name.to_owned(),
+                    labels: labels.clone(),
+                    value: u8::from(*is_set).to_string(),
+                })
+                .collect();
+            families.push(MetricFamily {
+                name: name.to_owned(),
+                metric_type: "stateset".to_owned(),
+                help: Some(
+                    "Whether the last parsed result of a command is the word in the label"
+                        .to_owned(),
+                ),
+                unit: None,
+                samples,
+            });
+        }
+        families
+    }
+
+    /// Starts a new run of the target, the series that are updated from now on belong to it
+    pub fn start_run(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Removes the series that were not updated in the last `stale_after` runs of the target
+    pub fn remove_stale_series(&self, target: &Target) {
+        let Some(StaleAfter::Runs(runs)) = target.stale_after else {
+            return;
+        };
+        let generation = self.generation.load(Ordering::Relaxed);
+        let is_stale = |updated: u64| generation.saturating_sub(updated) >= runs;
+
+        let mut series = self.series.lock().unwrap();
+        self.remove_series(target, &mut series, |_, update| is_stale(update.generation));
+        self.exposition
+            .lock()
+            .unwrap()
+            .retain(|_, (updated, _)| !is_stale(*updated));
+
+        // Commands that hang or are not executed anymore do not record their metrics
+        self.command_updates
+            .lock()
+            .unwrap()
+            .retain(|command_index, updated| {
+                if !is_stale(*updated) {
+                    return true;
+                }
+
+                let labels = command_labels(target, *command_index);
+                self.up.remove(&labels);
+                self.last_run_timestamp_seconds.remove(&labels);
+                self.last_success_timestamp_seconds.remove(&labels);
+                self.exit_code.remove(&labels);
+                self.terminated_by_signal.remove(&labels);
+                false
+            });
+    }
+
+    /// Removes the series of a command that did not succeed, if the target drops them on failure
+    pub fn remove_failed_command_series(&self, target: &Target, command_index: usize) {
+        if target.stale_after != Some(StaleAfter::Event(StaleEvent::OnFailure)) {
+            return;
+        }
+
+        let mut series = self.series.lock().unwrap();
+        self.remove_series(target, &mut series, |_, update| {
+            update.command_index == command_index
+        });
+        self.exposition.lock().unwrap().remove(&command_index);
+    }
+
+    /// Marks the per command metrics of the command as updated in the current run
+    fn command_updated(&self, command_index: usize) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.command_updates
+            .lock()
+            .unwrap()
+            .insert(command_index, generation);
+    }
+
+    fn series_update(&self, command_index: usize) -> SeriesUpdate {
+        SeriesUpdate {
+            command_index,
+            generation: self.generation.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Removes the series the predicate selects from the metrics of the parsed values
+    fn remove_series(
+        &self,
+        target: &Target,
+        series: &mut SeriesUpdates,
+        is_removed: impl Fn(&Vec<(String, String)>, &SeriesUpdate) -> bool,
+    ) {
+        series.retain(|labels, update| {
+            if !is_removed(labels, update) {
+                return true;
+            }
+
+            self.last_result.remove(labels);
+            self.last_duration.remove(labels);
+            for metric in self.value_groups.values() {
+                metric.remove(labels);
+            }
+            let mut states = self.state.lock().unwrap();
+            for word in target.value_map.keys() {
+                states.remove(&state_labels(target, labels, word));
+            }
+            false
+        });
+    }
+}
+
+/// The labels every series of a result starts with.
+/// The exit code is only added if the target enables the `exit_code_label`.
+fn result_labels(target: &Target, status: &ExitStatus) -> Vec<(String, String)> {
+    let mut labels = vec![("name".to_owned(), target.name.to_owned())];
+    if target.exit_code_label {
+        let exit_code = status.code().map_or(String::new(), |code| code.to_string());
+        labels.push(("exit_code".to_owned(), exit_code));
+    }
+    labels
+}
+
+/// The labels of the metrics that are recorded per command.
+/// The command is identified by its index in the `commands` of the target.
+fn command_labels(target: &Target, command_index: usize) -> Vec<(String, String)> {
+    vec![
+        ("name".to_owned(), target.name.to_owned()),
+        ("command".to_owned(), command_index.to_string()),
+    ]
+}
+
+/// The signal that terminated a process
+#[cfg(target_os = "linux")]
+fn termination_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+/// Processes on Windows are not terminated by signals
+#[cfg(target_os = "windows")]
+fn termination_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// The signal that kills a command that timed out
+#[cfg(target_os = "linux")]
+const TIMEOUT_SIGNAL: Option<i32> = Some(libc::SIGKILL);
+
+/// Commands on Windows that timed out are terminated without a signal
+#[cfg(target_os = "windows")]
+const TIMEOUT_SIGNAL: Option<i32> = None;
+
+/// The labels of the state series of a word, the state label is named like the metric
+fn state_labels(target: &Target, labels: &[(String, String)], word: &str) -> Vec<(String, String)> {
+    let mut state_labels = labels.to_vec();
+    state_labels.push((format!("{}_state", target.name), word.to_owned()));
+    state_labels
+}
+
+/// Checks if the metrics of the target are encoded with `encode_metrics` besides the registry,
+/// because its commands print metric families or it exports a state set
+pub fn serves_families(target: &Target) -> bool {
+    target.parser == OutputParser::Prometheus || target.state_set
+}
+
+/// Writes the metrics of the registry and the metric families of the targets that
+/// `serves_families` selects, like the families their commands printed, in the OpenMetrics format
+pub fn encode_metrics(
+    writer: &mut impl Write,
+    registry: &Registry,
+    exposition_targets: &[Arc<TargetMetrics>],
+) -> std::fmt::Result {
+    encode_registry(writer, registry)?;
+
+    let families: Vec<MetricFamily> = exposition_targets
+        .iter()
+        .flat_map(|metrics| metrics.families())
+        .collect();
+    exposition::encode(writer, &families)?;
+
+    encode_eof(writer)
+}
+
+/// Builds the RegEx of a target the way it is applied to the output of its commands
+pub fn target_regex(target: &Target) -> Result<Regex, String> {
+    match Regex::new(&format!(r"(?m){}", &*target.regex)) {
+        Ok(x) => Ok(x),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Applies the RegEx of the target to the stdout of a command (or parses it with the other
+/// parser of the target) and parses the result and the `value_groups` to f64. Depending on the `match_mode` of the
+/// target, there is a result for the first or for every match of the RegEx.
+/// The returned labels are the templated labels of the command.
+/// `json_paths` are the compiled JSONPaths of a target with the json parser.
+pub fn parse_output(
+    target: &Target,
+    command: &TargetCommand,
+    regex: &Regex,
+    json_paths: &JsonPaths,
+    std_out: &str,
+) -> Result<Vec<ParsedResult>, String> {
+    let results = match target.parser {
+        OutputParser::Regex => parse_regex_output(target, command, regex, std_out)?,
+        OutputParser::Json => json_parser::parse_output(target, command, json_paths, std_out)?,
+        OutputParser::Prometheus => {
+            return Ok(exposition::results(&exposition::parse_output(
+                command, std_out,
+            )?))
+        }
+    };
+
+    check_unique_labels(&results)?;
+    Ok(results)
+}
+
+/// Parses the matches of the RegEx in the output.
+/// With `match_mode: all`, matches whose values cannot be parsed are skipped, as long as one of them can be parsed.
+fn parse_regex_output(
+    target: &Target,
+    command: &TargetCommand,
+    regex: &Regex,
+    std_out: &str,
+) -> Result<Vec<ParsedResult>, String> {
+    let matches = find_matches(target, regex, std_out);
+    if matches.is_empty() {
+        return Err("RegEx did not find any captures in stdout".to_string());
+    }
 
-                templated_value = templated_value
-                    .replace(("{".to_owned() + group_name + "}").as_str(), group_content)
-                    .to_owned();
+    let mut results = vec![];
+    let mut first_error = None;
+    for captures in &matches {
+        match parse_captures(target, command, regex, captures, std_out) {
+            Ok(parsed) => results.push(parsed),
+            Err(e) if target.match_mode == MatchMode::All => {
+                warn!("Target '{}': Skipping a match: {e}", target.name);
+                first_error.get_or_insert(e);
             }
-            result_labels.push((label.to_owned(), templated_value.to_string()));
+            Err(e) => return Err(e),
         }
+    }
+
+    match first_error {
+        Some(e) if results.is_empty() => Err(e),
+        _ => Ok(results),
+    }
+}
 
-        // Simply parse the stdout to a f64 and return that or explode trying
-        let numeric_value = match cap.parse::<f64>() {
-            Err(_) => {
+/// Checks that no two results have the same labels, as they would update the same series
+fn check_unique_labels(results: &[ParsedResult]) -> Result<(), String> {
+    let mut label_sets = HashSet::new();
+    for parsed in results {
+        let mut labels = parsed.labels.clone();
+        labels.sort();
+        if !label_sets.insert(labels) {
+            return Err(format!(
+                "Multiple results have the labels {{{}}}, add a label that tells them apart",
+                parsed
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value:?}"))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Applies the RegEx to the trimmed stdout of a command.
+/// Returns the first or every match, depending on the `match_mode` of the target.
+pub fn find_matches<'a>(target: &Target, regex: &Regex, std_out: &'a str) -> Vec<Captures<'a>> {
+    match target.match_mode {
+        MatchMode::First => regex.captures(std_out.trim()).into_iter().collect(),
+        MatchMode::All => regex.captures_iter(std_out.trim()).collect(),
+    }
+}
+
+/// Templates the labels of the command and parses the values of a single match of the RegEx
+fn parse_captures(
+    target: &Target,
+    command: &TargetCommand,
+    regex: &Regex,
+    captures: &Captures,
+    std_out: &str,
+) -> Result<ParsedResult, String> {
+    let mut labels = vec![];
+    for (label, value) in &command.labels {
+        let mut templated_value = value.clone();
+        for group_name in regex.capture_names().flatten() {
+            let group_content = captures.name(group_name).map_or("", |m| m.as_str());
+
+            templated_value = templated_value
+                .replace(("{".to_owned() + group_name + "}").as_str(), group_content)
+                .to_owned();
+        }
+        labels.push((label.to_owned(), templated_value.to_string()));
+    }
+
+    // Simply parse the captures to a f64 and return them or explode trying
+    let mut values = vec![];
+    let mut state = None;
+    for group in target.value_selectors() {
+        let cap = captures.name(group).map_or("", |m| m.as_str());
+        if Some(group) == target.result_selector() {
+            state = mapped_state(target, cap);
+        }
+        match parse_target_value(target, command, cap) {
+            Err(e) => {
                 return Err(format!(
-                    "Could not parse capture to f64.\nCaptures: {captures:?}\nStdout:{std_out}"
+                    "Could not parse the group '{group}' to f64: {e}.\nCaptures: {captures:?}\nStdout:{std_out}"
                 ))
             }
-            Ok(c) => c,
+            Ok(c) => values.push((group.to_owned(), c)),
         };
+    }
 
-        self.last_result
-            .get_or_create(&result_labels)
-            .set(numeric_value);
+    Ok(ParsedResult {
+        captures: regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_owned(), captures.name(name)?.as_str().to_owned())))
+            .collect(),
+        labels,
+        values,
+        state,
+    })
+}
 
-        self.last_duration
-            .get_or_create(&result_labels)
-            .set(duration.as_millis() as i64);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encoded, output, registered_metrics};
+
+    #[test]
+    fn parse_output_templates_labels() {
+        let target = Target {
+            regex: r"^(?<mount>\S+) (?<result>\d+)$".to_string(),
+            regex_named_group: "result".to_string(),
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("mount".to_string(), "disk {mount}".to_string())]),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+
+        // The RegEx is applied in multi line mode to the trimmed output
+        let results = parse_output(
+            &target,
+            &command,
+            &regex,
+            &JsonPaths::default(),
+            "\n  header\n/home 42\n\n",
+        )
+        .unwrap();
+        let parsed = &results[0];
+
+        assert_eq!(parsed.values, vec![("result".to_string(), 42.0)]);
+        assert_eq!(
+            parsed.labels,
+            vec![("mount".to_string(), "disk /home".to_string())]
+        );
+    }
+
+    #[test]
+    fn parse_output_invalid_number() {
+        let target = Target {
+            regex: r"(?<result>\w+)".to_string(),
+            regex_named_group: "result".to_string(),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+
+        assert!(parse_output(
+            &target,
+            &TargetCommand::default(),
+            &regex,
+            &JsonPaths::default(),
+            "abc"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_output_match_mode_all_rows() {
+        let target = Target {
+            regex: r"^(?<mount>\S+) (?<result>\w+)$".to_string(),
+            regex_named_group: "result".to_string(),
+            match_mode: MatchMode::All,
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("mount".to_string(), "{mount}".to_string())]),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+
+        let parse =
+            |std_out| parse_output(&target, &command, &regex, &JsonPaths::default(), std_out);
+
+        // The row that cannot be parsed is skipped
+        let results = parse("/ 1\n/home abc\n/var 3").unwrap();
+        let mounts: Vec<&Vec<(String, String)>> = results.iter().map(|r| &r.labels).collect();
+        assert_eq!(
+            mounts,
+            [
+                &vec![("mount".to_string(), "/".to_string())],
+                &vec![("mount".to_string(), "/var".to_string())]
+            ]
+        );
+        assert!(parse("/ abc\n/home abc").is_err());
+
+        // Rows with the same labels would overwrite each other
+        let error = parse("/ 1\n/home 2\n/ 3").unwrap_err();
+        assert!(error.contains(r#"mount="/""#), "{error}");
+    }
+
+    #[test]
+    fn update_result_match_mode_all() {
+        let target = Target {
+            name: "df".to_string(),
+            regex: r"^(?<mount>\S+) (?<result>\d+)$".to_string(),
+            regex_named_group: "result".to_string(),
+            match_mode: MatchMode::All,
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("mount".to_string(), "{mount}".to_string())]),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        let results = metrics
+            .update_result(
+                &target,
+                0,
+                &command,
+                &regex,
+                &output("/ 1\n/home 2"),
+                &Duration::ZERO,
+            )
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(encoded(&registry).contains(r#"mount="/home"} 2.0"#));
+
+        // The series of a row that disappeared is removed
+        metrics
+            .update_result(
+                &target,
+                0,
+                &command,
+                &regex,
+                &output("/ 3"),
+                &Duration::ZERO,
+            )
+            .unwrap();
+        let body = encoded(&registry);
+        assert!(body.contains(r#"mount="/"} 3.0"#));
+        assert!(!body.contains(r#"mount="/home""#));
+    }
+
+    #[test]
+    fn update_exposition_rejects_registered_names() {
+        let target = Target {
+            name: "node".to_string(),
+            parser: OutputParser::Prometheus,
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+        metrics.reserve_names(registered_metric_names(&target).collect());
+
+        let update = |stdout: &str| {
+            metrics.update_result(
+                &target,
+                0,
+                &TargetCommand::default(),
+                &regex,
+                &output(stdout),
+                &Duration::ZERO,
+            )
+        };
+
+        let error = update("# TYPE node_up gauge\nnode_up 1\n").unwrap_err();
+        assert!(error.contains("'node_up'"), "{error}");
+        assert!(update("# TYPE node_runs_total counter\nnode_runs_total 1\n").is_err());
+        update("# TYPE node_files gauge\nnode_files 3\n").unwrap();
+
+        let mut body = String::new();
+        encode_metrics(&mut body, &registry, std::slice::from_ref(&metrics)).unwrap();
+        assert_eq!(body.matches("# TYPE node_up ").count(), 1);
+        assert_eq!(body.matches("# TYPE node_runs ").count(), 1);
+        assert!(body.contains("# TYPE node_files gauge\nnode_files 3\n"));
+    }
+
+    #[test]
+    fn update_exposition_rejects_conflicting_types() {
+        let target = Target {
+            name: "node".to_string(),
+            parser: OutputParser::Prometheus,
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, _registry) = registered_metrics(&target);
+        let update = |command_index: usize, stdout: &str| {
+            metrics.update_result(
+                &target,
+                command_index,
+                &TargetCommand::default(),
+                &regex,
+                &output(stdout),
+                &Duration::ZERO,
+            )
+        };
+
+        update(0, "# TYPE files gauge\nfiles 3\n").unwrap();
+        let error = update(1, "# TYPE files counter\nfiles_total 1\n").unwrap_err();
+        assert!(error.contains("'files'"), "{error}");
+        update(1, "# TYPE files gauge\nfiles 4\n").unwrap();
+        // The command can change the type of the families only it prints
+        update(
+            1,
+            "# TYPE files gauge\nfiles 4\n# TYPE dirs counter\ndirs_total 1\n",
+        )
+        .unwrap();
+        update(
+            1,
+            "# TYPE files gauge\nfiles 4\n# TYPE dirs gauge\ndirs 1\n",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn update_result_exit_code_label() {
+        let target = Target {
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            exit_code_label: true,
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let results = TargetMetrics::new(&target)
+            .update_result(
+                &target,
+                0,
+                &TargetCommand::default(),
+                &regex,
+                &output("1"),
+                &Duration::ZERO,
+            )
+            .unwrap();
+        assert!(results[0]
+            .labels
+            .contains(&("exit_code".to_string(), "0".to_string())));
+    }
+
+    #[test]
+    fn update_result_state_set() {
+        let target = Target {
+            name: "unit".to_string(),
+            regex: r"(?<result>\w+)".to_string(),
+            regex_named_group: "result".to_string(),
+            value_map: HashMap::from([("active".to_string(), 1.0), ("failed".to_string(), 0.0)]),
+            state_set: true,
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        let results = metrics
+            .update_result(
+                &target,
+                0,
+                &TargetCommand::default(),
+                &regex,
+                &output("failed\n"),
+                &Duration::ZERO,
+            )
+            .unwrap();
+        assert_eq!(results[0].values, vec![("result".to_string(), 0.0)]);
+        assert_eq!(results[0].state, Some("failed".to_string()));
+
+        let mut body = String::new();
+        encode_metrics(&mut body, &registry, std::slice::from_ref(&metrics)).unwrap();
+        assert!(body.contains("# TYPE unit_state stateset\n"));
+        assert!(body.contains(r#"unit_state{name="unit",unit_state="failed"} 1"#));
+        assert!(body.contains(r#"unit_state{name="unit",unit_state="active"} 0"#));
+    }
+
+    #[test]
+    fn update_result_counter_reset() {
+        let target = Target {
+            name: "net".to_string(),
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            metric_type: MetricType::Counter,
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        for stdout in ["100", "150", "20"] {
+            metrics
+                .update_result(
+                    &target,
+                    0,
+                    &TargetCommand::default(),
+                    &regex,
+                    &output(stdout),
+                    &Duration::ZERO,
+                )
+                .unwrap();
+        }
+
+        // The counter restarted at 20 after it reached 150
+        let body = encoded(&registry);
+        assert!(body.contains("# TYPE net_result counter"));
+        assert!(body.contains(r#"net_result_total{name="net"} 170.0"#));
+    }
+
+    #[test]
+    fn update_result_invalid_value_updates_nothing() {
+        let target = Target {
+            name: "net".to_string(),
+            regex: r"^(?<row>\w+) (?<result>\d+)$".to_string(),
+            regex_named_group: "result".to_string(),
+            match_mode: MatchMode::All,
+            transform: Some("value - 4".to_string()),
+            metric_type: MetricType::Counter,
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("row".to_string(), "{row}".to_string())]),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        let result = metrics.update_result(
+            &target,
+            0,
+            &command,
+            &regex,
+            &output("a 5\nb 3"),
+            &Duration::ZERO,
+        );
+        assert!(result.is_err());
+
+        // The valid row is not updated either
+        let body = encoded(&registry);
+        assert!(!body.contains("net_result_total{"), "{body}");
+    }
+
+    #[test]
+    fn value_metric_histogram_buckets() {
+        let target = Target {
+            metric_type: MetricType::Histogram,
+            buckets: Some(vec![1.0, 10.0]),
+            ..Default::default()
+        };
+        let metric = ValueMetric::new(&target);
+        let mut registry = Registry::default();
+        metric.register(&mut registry, "latency", "Latency");
+
+        let labels = vec![("name".to_string(), "latency".to_string())];
+        metric.update(&labels, 0.5);
+        metric.update(&labels, 5.0);
+
+        let body = encoded(&registry);
+        assert!(body.contains(r#"latency_bucket{le="1.0",name="latency"} 1"#));
+        assert!(body.contains(r#"latency_bucket{le="10.0",name="latency"} 2"#));
+        assert!(body.contains(r#"latency_count{name="latency"} 2"#));
+    }
+
+    #[test]
+    fn remove_stale_series_after_runs() {
+        let target = Target {
+            name: "disk".to_string(),
+            regex: r"^(?<kind>\w+) (?<result>\d+)$".to_string(),
+            regex_named_group: "result".to_string(),
+            stale_after: Some(StaleAfter::Runs(2)),
+            ..Default::default()
+        };
+        let command = TargetCommand {
+            labels: HashMap::from([("kind".to_string(), "{kind}".to_string())]),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        let run = |stdout: &str| {
+            metrics.start_run();
+            metrics
+                .update_result(
+                    &target,
+                    0,
+                    &command,
+                    &regex,
+                    &output(stdout),
+                    &Duration::ZERO,
+                )
+                .unwrap();
+            metrics.remove_stale_series(&target);
+
+            encoded(&registry)
+        };
+
+        // The label value changed, the old series is removed after two runs without an update
+        run("ssd 1");
+        assert!(run("hdd 2").contains(r#"kind="ssd""#));
+        assert!(!run("hdd 3").contains(r#"kind="ssd""#));
+    }
+
+    #[test]
+    fn remove_failed_command_series_on_failure() {
+        let target = Target {
+            name: "unit".to_string(),
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            stale_after: Some(StaleAfter::Event(StaleEvent::OnFailure)),
+            ..Default::default()
+        };
+        let regex = target_regex(&target).unwrap();
+        let (metrics, registry) = registered_metrics(&target);
+
+        metrics
+            .update_result(
+                &target,
+                0,
+                &TargetCommand::default(),
+                &regex,
+                &output("1"),
+                &Duration::ZERO,
+            )
+            .unwrap();
+        metrics.remove_failed_command_series(&target, 0);
 
-        Ok(())
+        let body = encoded(&registry);
+        assert!(!body.contains("unit_result{"));
     }
 }
@@ -0,0 +1,727 @@
+use crate::config::schema::{OverlapPolicy, Schema, Target, TargetCommand};
+use crate::prometheus::{target_regex, ParsedResult, RunOutcome, TargetMetrics};
+use crate::shell_commands::ShellCommand;
+use log::{info, trace, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::io;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, Semaphore, SemaphorePermit};
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+/// Limits the number of commands that are executed at the same time
+#[derive(Default)]
+pub struct ConcurrencyLimits {
+    /// The limit for all commands
+    global: Option<Arc<Semaphore>>,
+    /// The limits of the named concurrency groups
+    groups: HashMap<String, Arc<Semaphore>>,
+}
+
+impl ConcurrencyLimits {
+    pub fn from_config(config: &Schema) -> Self {
+        ConcurrencyLimits {
+            global: config
+                .max_concurrent_commands
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            groups: config
+                .concurrency_groups
+                .iter()
+                .map(|(name, limit)| (name.clone(), Arc::new(Semaphore::new(*limit))))
+                .collect(),
+        }
+    }
+
+    /// Waits until a command of the target is allowed to run.
+    /// The command may run for as long as the returned permits are kept.
+    async fn acquire(&self, target: &Target) -> Vec<SemaphorePermit<'_>> {
+        let group = match &target.concurrency_group {
+            Some(name) => self.groups.get(name),
+            None => None,
+        };
+
+        // The group is acquired first, so that waiting for it does not block a global slot
+        let mut permits = vec![];
+        for semaphore in [group, self.global.as_ref()].into_iter().flatten() {
+            // The semaphores are never closed
+            if let Ok(permit) = semaphore.acquire().await {
+                permits.push(permit);
+            }
+        }
+        permits
+    }
+}
+
+/// Runs a target when its schedule ticks and makes sure that runs of the
+/// target do not overlap in a way that is not allowed by its overlap policy
+pub struct TargetRunner {
+    target: Arc<Target>,
+    metrics: Arc<TargetMetrics>,
+    limits: Arc<ConcurrencyLimits>,
+    /// Locked for as long as a run of the target is in progress
+    running: Arc<AsyncMutex<()>>,
+    /// Set while a run is waiting for the current run to finish
+    queued: Arc<AtomicBool>,
+    /// The run that is currently in progress
+    current: Arc<Mutex<Option<AbortHandle>>>,
+}
+
+impl TargetRunner {
+    pub fn new(
+        target: Target,
+        metrics: Arc<TargetMetrics>,
+        limits: Arc<ConcurrencyLimits>,
+    ) -> Self {
+        TargetRunner {
+            target: Arc::new(target),
+            metrics,
+            limits,
+            running: Default::default(),
+            queued: Default::default(),
+            current: Default::default(),
+        }
+    }
+
+    /// Creates the runner of a changed target that continues after this runner.
+    /// Its runs follow the overlap policy against the run of this runner that may still be in progress.
+    pub fn restart(
+        &self,
+        target: Target,
+        metrics: Arc<TargetMetrics>,
+        limits: Arc<ConcurrencyLimits>,
+    ) -> Self {
+        TargetRunner {
+            target: Arc::new(target),
+            metrics,
+            limits,
+            running: self.running.clone(),
+            queued: self.queued.clone(),
+            current: self.current.clone(),
+        }
+    }
+
+    /// Starts a new run of the target in a separate task.
+    /// Must be called from within a tokio runtime.
+    pub fn trigger(&self) {
+        match self.target.overlap {
+            OverlapPolicy::Skip => match self.running.clone().try_lock_owned() {
+                Ok(guard) => {
+                    self.spawn_run(async { guard });
+                }
+                Err(_) => self.skip(),
+            },
+            OverlapPolicy::Queue => match self.running.clone().try_lock_owned() {
+                Ok(guard) => {
+                    self.spawn_run(async { guard });
+                }
+                // Only one run waits for the current one, everything beyond that is skipped
+                Err(_) if self.queued.swap(true, Ordering::SeqCst) => self.skip(),
+                Err(_) => {
+                    let running = self.running.clone();
+                    let queued = self.queued.clone();
+                    self.spawn_run(async move {
+                        let guard = running.lock_owned().await;
+                        queued.store(false, Ordering::SeqCst);
+                        guard
+                    });
+                }
+            },
+            OverlapPolicy::KillPrevious => {
+                let previous = self.current.lock().unwrap().take();
+                if let Some(previous) = previous {
+                    if !previous.is_finished() {
+                        warn!(
+                            "Target '{}': Killing the previous run that is still in progress",
+                            self.target.name
+                        );
+                        // Dropping the run kills the processes it started
+                        previous.abort();
+                    }
+                }
+
+                // The aborted run releases the lock once it is dropped
+                let running = self.running.clone();
+                let handle = self.spawn_run(async move { running.lock_owned().await });
+                *self.current.lock().unwrap() = Some(handle);
+            }
+        }
+    }
+
+    /// Runs the target after `acquire` resolved, the value it resolves to is kept until the run finished
+    fn spawn_run<G: Send + 'static>(
+        &self,
+        acquire: impl std::future::Future<Output = G> + Send + 'static,
+    ) -> AbortHandle {
+        // Clone the state variables so that they can be moved into the task
+        let target = self.target.clone();
+        let metrics = self.metrics.clone();
+        let limits = self.limits.clone();
+
+        tokio::spawn(async move {
+            let _guard = acquire.await;
+            match handle_target(metrics, &limits, &target).await {
+                Ok(reports) => match reports.iter().find_map(|r| r.result.as_ref().err()) {
+                    None => trace!("Command executed successfully"),
+                    Some(e) => warn!("Command execution failed {e}"),
+                },
+                Err(e) => warn!("Command execution failed {e}"),
+            };
+        })
+        .abort_handle()
+    }
+
+    fn skip(&self) {
+        warn!(
+            "Target '{}': Skipping run, because the previous run is still in progress",
+            self.target.name
+        );
+        self.metrics.record_skipped(&self.target);
+        // The skipped run ages the series like a run without updates, so the series
+        // of a run that hangs become stale as well
+        self.metrics.start_run();
+        self.metrics.remove_stale_series(&self.target);
+    }
+}
+
+/// What happened when a command of a target was executed
+#[derive(Debug)]
+pub struct CommandReport {
+    /// The command as it is written in the config file
+    pub command: String,
+    pub outcome: RunOutcome,
+    /// The output of the process, if it could be started and did not time out
+    pub output: Option<Output>,
+    pub duration: Duration,
+    /// The parsed values of the matches or the reason why the command failed
+    pub result: Result<Vec<ParsedResult>, String>,
+}
+
+/// This method is called for each command target when the timer ticks
+/// The commands are executed and their output interpreted until one of them fails.
+///
+/// Returns a report of every executed command or an error if the target could not be run at all.
+pub async fn handle_target(
+    state: Arc<TargetMetrics>,
+    limits: &ConcurrencyLimits,
+    target: &Target,
+) -> Result<Vec<CommandReport>, String> {
+    let regex = target_regex(target)?;
+
+    info!("Handling target '{}'", target.name);
+    state.start_run();
+
+    let mut reports = vec![];
+    for (index, command) in target.commands.iter().enumerate() {
+        let report =
+            handle_target_command(state.clone(), limits, &regex, target, index, command).await;
+        let failed = report.result.is_err();
+        if let Err(e) = &report.result {
+            warn!(
+                "Target: '{}' Error handling command '{}' {}",
+                target.name, command, e
+            );
+        }
+
+        reports.push(report);
+        if failed {
+            break;
+        }
+    }
+    for index in reports.len()..target.commands.len() {
+        state.record_not_run(target, index);
+    }
+
+    state.remove_stale_series(target);
+    Ok(reports)
+}
+
+async fn handle_target_command(
+    state: Arc<TargetMetrics>,
+    limits: &ConcurrencyLimits,
+    regex: &Regex,
+    target: &Target,
+    command_index: usize,
+    command: &TargetCommand,
+) -> CommandReport {
+    let timeout = command.timeout.or(target.timeout).map(Duration::from);
+    let cmd = match build_shell_command(target, command) {
+        Ok(cmd) => cmd
+            .with_timeout(timeout)
+            .with_env(&command.env, command.clear_env)
+            .with_cwd(command.cwd.clone())
+            .with_stdin(command.stdin.clone()),
+        Err(e) => {
+            state.record_missing_exit_status(target, command_index, RunOutcome::SpawnError);
+            return CommandReport {
+                command: command.to_string(),
+                outcome: RunOutcome::SpawnError,
+                output: None,
+                duration: Duration::ZERO,
+                result: Err(e),
+            };
+        }
+    };
+
+    let wait_start = Instant::now();
+    let _permits = limits.acquire(target).await;
+    state.record_queue_wait(target, &wait_start.elapsed());
+
+    let (execution_result, duration) = cmd.execute().await;
+
+    let (outcome, output, result) = match execution_result {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => (
+            RunOutcome::Timeout,
+            None,
+            Err(format!("Command timed out: {e}")),
+        ),
+        Err(e) => (
+            RunOutcome::SpawnError,
+            None,
+            Err(format!("Error executing command: {e}")),
+        ),
+        Ok(x) if !is_success_exit_status(target, &x.status) => {
+            let message = format!("Command exited with unsuccessful status: {}", x.status);
+            (RunOutcome::Failure, Some(x), Err(message))
+        }
+        Ok(x) => match state.update_result(target, command_index, command, regex, &x, &duration) {
+            Ok(parsed) => (RunOutcome::Success, Some(x), Ok(parsed)),
+            Err(e) => (
+                RunOutcome::ParseError,
+                Some(x),
+                Err(format!("Error updating result: {}", e)),
+            ),
+        },
+    };
+
+    state.record_duration(target, outcome, &duration);
+    state.record_outcome(target, outcome);
+    state.record_run(target, command_index, outcome);
+    match &output {
+        Some(output) => state.record_exit_status(target, command_index, &output.status),
+        None => state.record_missing_exit_status(target, command_index, outcome),
+    }
+    if outcome != RunOutcome::Success {
+        state.remove_failed_command_series(target, command_index);
+    }
+    CommandReport {
+        command: command.to_string(),
+        outcome,
+        output,
+        duration,
+        result,
+    }
+}
+
+/// Creates the process for a command, either directly from its argv or with the shell of the target
+fn build_shell_command(target: &Target, command: &TargetCommand) -> Result<ShellCommand, String> {
+    match (&command.argv, &command.exec, &target.shell) {
+        (Some(argv), _, _) => Ok(ShellCommand::from_argv(argv)),
+        (None, Some(exec), Some(shell)) => Ok(ShellCommand::with_shell(shell, exec)),
+        (None, Some(exec), None) => Ok(ShellCommand::new(exec)),
+        (None, None, _) => Err("The command has neither 'exec' nor 'argv'".to_string()),
+    }
+}
+
+/// Checks if the exit status is listed in the `success_exit_codes` of the target.
+/// Processes that were terminated by a signal have no exit code and never succeed.
+fn is_success_exit_status(target: &Target, status: &ExitStatus) -> bool {
+    match status.code() {
+        Some(code) => target.success_exit_codes.contains(&code),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+    use crate::config::schema::StaleAfter;
+    use crate::test_utils::{encoded, registered_metrics};
+    use prometheus_client::registry::Registry;
+
+    /// A target with a command that runs for a while
+    fn slow_target(overlap: OverlapPolicy) -> Target {
+        Target {
+            name: "slow".to_string(),
+            commands: vec![TargetCommand {
+                exec: Some("sleep 0.5; echo 1".to_string()),
+                ..Default::default()
+            }],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0],
+            overlap,
+            ..Default::default()
+        }
+    }
+
+    fn runs(metrics: &TargetMetrics, target: &Target, outcome: RunOutcome) -> u64 {
+        metrics
+            .runs_total
+            .get_or_create(&vec![
+                ("name".to_owned(), target.name.to_owned()),
+                ("outcome".to_owned(), outcome.as_str().to_owned()),
+            ])
+            .get()
+    }
+
+    fn skipped_runs(metrics: &TargetMetrics, target: &Target) -> u64 {
+        metrics
+            .skipped_runs_total
+            .get_or_create(&vec![("name".to_owned(), target.name.to_owned())])
+            .get()
+    }
+
+    /// Waits until the successful runs of the target reach the expected number.
+    /// The paused clock advances instantly, so the deadline is checked against the real clock.
+    async fn wait_for_runs(metrics: &TargetMetrics, target: &Target, expected: u64) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while runs(metrics, target, RunOutcome::Success) < expected {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "The runs did not finish"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trigger_skips_overlapping_runs() {
+        let target = slow_target(OverlapPolicy::Skip);
+        let metrics = Arc::new(TargetMetrics::new(&target));
+        let runner = TargetRunner::new(target.clone(), metrics.clone(), Default::default());
+
+        runner.trigger();
+        runner.trigger();
+        runner.trigger();
+        wait_for_runs(&metrics, &target, 1).await;
+        assert_eq!(skipped_runs(&metrics, &target), 2);
+
+        // The next run starts normally once the previous one finished
+        runner.trigger();
+        wait_for_runs(&metrics, &target, 2).await;
+        assert_eq!(skipped_runs(&metrics, &target), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trigger_queues_one_run() {
+        let target = slow_target(OverlapPolicy::Queue);
+        let metrics = Arc::new(TargetMetrics::new(&target));
+        let runner = TargetRunner::new(target.clone(), metrics.clone(), Default::default());
+
+        runner.trigger();
+        runner.trigger();
+        runner.trigger();
+        assert_eq!(skipped_runs(&metrics, &target), 1);
+
+        wait_for_runs(&metrics, &target, 2).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(runs(&metrics, &target, RunOutcome::Success), 2);
+        assert_eq!(skipped_runs(&metrics, &target), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trigger_kills_previous_run() {
+        let target = slow_target(OverlapPolicy::KillPrevious);
+        let metrics = Arc::new(TargetMetrics::new(&target));
+        let runner = TargetRunner::new(target.clone(), metrics.clone(), Default::default());
+
+        runner.trigger();
+        // Let the first run start its command
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        runner.trigger();
+
+        wait_for_runs(&metrics, &target, 1).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // The killed run does not record anything
+        assert_eq!(runs(&metrics, &target, RunOutcome::Success), 1);
+        assert_eq!(skipped_runs(&metrics, &target), 0);
+    }
+
+    #[tokio::test]
+    async fn trigger_skipped_runs_age_series() {
+        let target = Target {
+            stale_after: Some(StaleAfter::Runs(2)),
+            ..slow_target(OverlapPolicy::Skip)
+        };
+        let (metrics, registry) = registered_metrics(&target);
+        handle_target(metrics.clone(), &ConcurrencyLimits::default(), &target)
+            .await
+            .unwrap();
+
+        // The next run hangs, so the series of the first run are not updated anymore
+        let hanging = Target {
+            commands: vec![TargetCommand {
+                exec: Some("sleep 10; echo 1".to_string()),
+                ..Default::default()
+            }],
+            ..target.clone()
+        };
+        let runner = TargetRunner::new(hanging, metrics.clone(), Default::default());
+        runner.trigger();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let body = encoded(&registry);
+        assert!(body.contains(r#"slow_result{name="slow"} 1"#));
+        assert!(body.contains(r#"slow_up{name="slow",command="0"} 1"#));
+
+        runner.trigger();
+        assert_eq!(skipped_runs(&metrics, &target), 1);
+        let body = encoded(&registry);
+        assert!(!body.contains("slow_result{"));
+        assert!(!body.contains("slow_up{"));
+        assert!(!body.contains("slow_last_run_timestamp_seconds{"));
+        assert!(!body.contains("slow_exit_code{"));
+    }
+
+    #[tokio::test]
+    async fn handle_target_reports_parsed_result() {
+        let target = Target {
+            name: "disk".to_string(),
+            commands: vec![
+                TargetCommand {
+                    exec: Some("echo disk 42".to_string()),
+                    labels: HashMap::from([("kind".to_string(), "{kind}".to_string())]),
+                    ..Default::default()
+                },
+                TargetCommand {
+                    exec: Some("echo nope".to_string()),
+                    ..Default::default()
+                },
+                TargetCommand {
+                    exec: Some("echo never 1".to_string()),
+                    ..Default::default()
+                },
+            ],
+            regex: r"(?<kind>\w+) (?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0],
+            ..Default::default()
+        };
+
+        let reports = handle_target(
+            Arc::new(TargetMetrics::new(&target)),
+            &ConcurrencyLimits::default(),
+            &target,
+        )
+        .await
+        .unwrap();
+
+        // The commands after the failed one are not executed
+        assert_eq!(reports.len(), 2);
+
+        assert_eq!(reports[0].outcome, RunOutcome::Success);
+        let parsed = &reports[0].result.as_ref().unwrap()[0];
+        assert_eq!(parsed.values, vec![("result".to_string(), 42.0)]);
+        assert!(parsed
+            .captures
+            .contains(&("kind".to_string(), "disk".to_string())));
+        assert!(parsed
+            .labels
+            .contains(&("kind".to_string(), "disk".to_string())));
+
+        assert_eq!(reports[1].outcome, RunOutcome::ParseError);
+        assert!(reports[1].result.is_err());
+    }
+
+    #[tokio::test]
+    async fn handle_target_waits_for_concurrency_group() {
+        let config = Schema {
+            concurrency_groups: HashMap::from([("storage".to_string(), 1)]),
+            ..Default::default()
+        };
+        let limits = ConcurrencyLimits::from_config(&config);
+        let mut registry = Registry::default();
+        let targets: Vec<Target> = ["first", "second"]
+            .into_iter()
+            .map(|name| Target {
+                name: name.to_string(),
+                concurrency_group: Some("storage".to_string()),
+                ..slow_target(OverlapPolicy::Skip)
+            })
+            .collect();
+        let metrics: Vec<Arc<TargetMetrics>> = targets
+            .iter()
+            .map(|target| {
+                let metrics = Arc::new(TargetMetrics::new(target));
+                metrics.register(&mut registry, target);
+                metrics
+            })
+            .collect();
+
+        let start = Instant::now();
+        let (first, second) = tokio::join!(
+            handle_target(metrics[0].clone(), &limits, &targets[0]),
+            handle_target(metrics[1].clone(), &limits, &targets[1]),
+        );
+        first.unwrap();
+        second.unwrap();
+        // Each command runs 0.5s, so the second one could only start after the first one
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let body = encoded(&registry);
+        let mut waits: Vec<f64> = targets
+            .iter()
+            .map(|target| {
+                let prefix = format!(r#"{0}_queue_wait_seconds_sum{{name="{0}"}} "#, target.name);
+                let line = body.lines().find(|l| l.starts_with(&prefix)).unwrap();
+                line[prefix.len()..].parse().unwrap()
+            })
+            .collect();
+        waits.sort_by(f64::total_cmp);
+        assert!(waits[0] < 0.1, "{waits:?}");
+        assert!(waits[1] > 0.4, "{waits:?}");
+    }
+
+    #[tokio::test]
+    async fn handle_target_observes_duration() {
+        let target = Target {
+            name: "echo".to_string(),
+            commands: vec![TargetCommand {
+                exec: Some("echo 1".to_string()),
+                ..Default::default()
+            }],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0],
+            duration_buckets: Some(vec![60.0]),
+            legacy_duration_gauge: false,
+            ..Default::default()
+        };
+        let (metrics, registry) = registered_metrics(&target);
+
+        handle_target(metrics, &ConcurrencyLimits::default(), &target)
+            .await
+            .unwrap();
+
+        let body = encoded(&registry);
+        assert!(body.contains(
+            r#"echo_duration_seconds_bucket{le="60.0",name="echo",outcome="success"} 1"#
+        ));
+        assert!(!body.contains("echo_duration "));
+    }
+
+    #[tokio::test]
+    async fn handle_target_records_up() {
+        // The commands have the same labels, but their series are kept apart
+        let command = |exec: &str| TargetCommand {
+            exec: Some(exec.to_string()),
+            labels: HashMap::from([("check".to_string(), "{result}".to_string())]),
+            ..Default::default()
+        };
+        let target = Target {
+            name: "check".to_string(),
+            commands: vec![command("echo 1"), command("echo 1"), command("echo 1")],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0],
+            ..Default::default()
+        };
+        let (metrics, registry) = registered_metrics(&target);
+
+        handle_target(metrics.clone(), &ConcurrencyLimits::default(), &target)
+            .await
+            .unwrap();
+        let body = encoded(&registry);
+        assert!(body.contains(r#"check_up{name="check",command="2"} 1"#));
+
+        // The middle command fails, the one after it is not executed
+        let target = Target {
+            commands: vec![command("echo 1"), command("exit 3"), command("echo 1")],
+            ..target
+        };
+        handle_target(metrics, &ConcurrencyLimits::default(), &target)
+            .await
+            .unwrap();
+
+        let body = encoded(&registry);
+        assert!(body.contains(r#"check_up{name="check",command="0"} 1"#));
+        assert!(body.contains(r#"check_up{name="check",command="1"} 0"#));
+        assert!(body.contains(r#"check_up{name="check",command="2"} 0"#));
+        assert!(body.contains(r#"check_last_run_timestamp_seconds{name="check",command="1"}"#));
+        assert!(body.contains(r#"check_last_success_timestamp_seconds{name="check",command="0"}"#));
+    }
+
+    #[tokio::test]
+    async fn handle_target_records_exit_status() {
+        let target = Target {
+            name: "exit".to_string(),
+            commands: vec![
+                TargetCommand {
+                    exec: Some("echo 1; exit 3".to_string()),
+                    ..Default::default()
+                },
+                TargetCommand {
+                    exec: Some("kill -9 $$".to_string()),
+                    ..Default::default()
+                },
+            ],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0, 3],
+            ..Default::default()
+        };
+        let (metrics, registry) = registered_metrics(&target);
+
+        handle_target(metrics, &ConcurrencyLimits::default(), &target)
+            .await
+            .unwrap();
+
+        let body = encoded(&registry);
+        assert!(body.contains(r#"exit_exit_code{name="exit",command="0"} 3"#));
+        assert!(body.contains(r#"exit_terminated_by_signal{name="exit",command="0"} 0"#));
+        assert!(body.contains(r#"exit_terminated_by_signal{name="exit",command="1"} 9"#));
+        assert!(!body.contains(r#"exit_exit_code{name="exit",command="1"}"#));
+    }
+
+    #[tokio::test]
+    async fn handle_target_removes_exit_status_on_timeout() {
+        let target = Target {
+            name: "slow".to_string(),
+            commands: vec![TargetCommand {
+                exec: Some("sleep 0.2; echo 1; exit 3".to_string()),
+                ..Default::default()
+            }],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0, 3],
+            ..Default::default()
+        };
+        let (metrics, registry) = registered_metrics(&target);
+        let limits = ConcurrencyLimits::default();
+
+        handle_target(metrics.clone(), &limits, &target)
+            .await
+            .unwrap();
+        let body = encoded(&registry);
+        assert!(body.contains(r#"slow_exit_code{name="slow",command="0"} 3"#));
+
+        // The command that timed out was killed
+        let timed_out = Target {
+            timeout: Some(Duration::from_millis(50).into()),
+            ..target.clone()
+        };
+        handle_target(metrics.clone(), &limits, &timed_out)
+            .await
+            .unwrap();
+        let body = encoded(&registry);
+        assert!(!body.contains("slow_exit_code{"));
+        assert!(body.contains(r#"slow_terminated_by_signal{name="slow",command="0"} 9"#));
+
+        // The command that could not be started has no exit status
+        let not_started = Target {
+            commands: vec![TargetCommand {
+                cwd: Some("/nonexistent/directory".to_string()),
+                ..target.commands[0].clone()
+            }],
+            ..target
+        };
+        handle_target(metrics, &limits, &not_started).await.unwrap();
+        let body = encoded(&registry);
+        assert!(!body.contains("slow_exit_code{"));
+        assert!(!body.contains("slow_terminated_by_signal{"));
+    }
+}
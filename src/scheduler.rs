@@ -0,0 +1,287 @@
+use crate::config::schema::{MissedTickBehavior, Target};
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use log::warn;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::time::{interval_at, sleep, Instant};
+
+/// The longest time the cron scheduler sleeps before checking the wall clock again.
+/// This keeps executions on time if the system clock is changed.
+const MAX_CRON_SLEEP: Duration = Duration::from_secs(60);
+
+/// When a target is executed
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Execute the target in a fixed interval
+    Interval {
+        period: Duration,
+        missed_tick_behavior: MissedTickBehavior,
+    },
+    /// Execute the target at the wall clock times of a cron expression
+    Cron {
+        cron: Box<Cron>,
+        /// The time zone of the cron expression, `None` is the local time zone
+        timezone: Option<Tz>,
+    },
+}
+
+impl Schedule {
+    /// Builds the schedule from either the `run_every` or the `schedule` field of a target.
+    /// Exactly one of them has to be set.
+    pub fn from_target(target: &Target) -> Result<Schedule, String> {
+        match (&target.run_every, &target.schedule) {
+            (Some(_), Some(_)) => {
+                Err("Only one of 'run_every' and 'schedule' can be set".to_string())
+            }
+            (None, None) => Err("Either 'run_every' or 'schedule' has to be set".to_string()),
+            (Some(run_every), None) => {
+                if target.timezone.is_some() {
+                    return Err("'timezone' can only be used together with 'schedule'".to_string());
+                }
+
+                let period: Duration = (*run_every).into();
+                if period.is_zero() {
+                    return Err("'run_every' has to be longer than zero".to_string());
+                }
+
+                Ok(Schedule::Interval {
+                    period,
+                    missed_tick_behavior: target.missed_tick_behavior,
+                })
+            }
+            (None, Some(schedule)) => {
+                let cron = match Cron::new(schedule).with_seconds_optional().parse() {
+                    Ok(x) => x,
+                    Err(e) => return Err(format!("Invalid cron expression '{schedule}': {e}")),
+                };
+
+                let timezone = match &target.timezone {
+                    None => None,
+                    Some(name) => match name.parse::<Tz>() {
+                        Ok(x) => Some(x),
+                        Err(e) => return Err(format!("Invalid time zone '{name}': {e}")),
+                    },
+                };
+
+                Ok(Schedule::Cron {
+                    cron: Box::new(cron),
+                    timezone,
+                })
+            }
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Interval { period, .. } => write!(f, "every {period:?}"),
+            Schedule::Cron {
+                cron,
+                timezone: None,
+            } => write!(f, "at '{}' local time", cron.pattern),
+            Schedule::Cron {
+                cron,
+                timezone: Some(tz),
+            } => write!(f, "at '{}' {tz}", cron.pattern),
+        }
+    }
+}
+
+impl From<MissedTickBehavior> for tokio::time::MissedTickBehavior {
+    fn from(value: MissedTickBehavior) -> Self {
+        match value {
+            MissedTickBehavior::Burst => tokio::time::MissedTickBehavior::Burst,
+            MissedTickBehavior::Delay => tokio::time::MissedTickBehavior::Delay,
+            MissedTickBehavior::Skip => tokio::time::MissedTickBehavior::Skip,
+        }
+    }
+}
+
+/// Calls `job` according to the schedule forever
+pub async fn run(schedule: Schedule, job: impl FnMut()) {
+    match schedule {
+        Schedule::Interval {
+            period,
+            missed_tick_behavior,
+        } => run_interval(period, missed_tick_behavior, job).await,
+        Schedule::Cron { cron, timezone } => run_cron(&cron, timezone, job).await,
+    }
+}
+
+/// Calls `job` every `period` forever, the first call happens one period from now.
+///
+/// The ticks are calculated from the start time instead of the end of the previous
+/// tick, so the cadence does not drift no matter how long the scheduler runs.
+/// `job` should not block, long-running work has to be spawned as a separate task.
+pub async fn run_interval(
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+    mut job: impl FnMut(),
+) {
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(missed_tick_behavior.into());
+
+    loop {
+        interval.tick().await;
+        job();
+    }
+}
+
+/// Calls `job` at every occurrence of the cron expression.
+/// Returns if the cron expression has no future occurrences.
+pub async fn run_cron(cron: &Cron, timezone: Option<Tz>, mut job: impl FnMut()) {
+    loop {
+        let next = match next_occurrence(cron, timezone, Utc::now()) {
+            Some(x) => x,
+            None => {
+                warn!(
+                    "The cron expression '{}' has no next occurrence",
+                    cron.pattern
+                );
+                return;
+            }
+        };
+
+        // Wait until the wall clock reached the next occurrence
+        while let Ok(remaining) = (next - Utc::now()).to_std() {
+            if remaining.is_zero() {
+                break;
+            }
+            sleep(remaining.min(MAX_CRON_SLEEP)).await;
+        }
+
+        job();
+    }
+}
+
+/// Finds the first time after `after` that matches the cron expression in the time zone
+pub fn next_occurrence(
+    cron: &Cron,
+    timezone: Option<Tz>,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let next = match timezone {
+        Some(tz) => cron
+            .find_next_occurrence(&after.with_timezone(&tz), false)
+            .map(|x| x.with_timezone(&Utc)),
+        None => cron
+            .find_next_occurrence(&after.with_timezone(&Local), false)
+            .map(|x| x.with_timezone(&Utc)),
+    };
+
+    next.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::{Arc, Mutex};
+
+    /// Starts the scheduler in a background task and returns the times of all ticks
+    fn record_ticks(
+        period: Duration,
+        missed_tick_behavior: MissedTickBehavior,
+    ) -> Arc<Mutex<Vec<Instant>>> {
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let task_ticks = ticks.clone();
+        tokio::spawn(run_interval(period, missed_tick_behavior, move || {
+            task_ticks.lock().unwrap().push(Instant::now())
+        }));
+        ticks
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_interval_does_not_drift() {
+        let period = Duration::from_secs(7);
+        let start = Instant::now();
+        let ticks = record_ticks(period, MissedTickBehavior::Skip);
+
+        // Three days of uptime
+        tokio::time::sleep(Duration::from_secs(3 * 24 * 60 * 60)).await;
+
+        let ticks = ticks.lock().unwrap();
+        assert_eq!(ticks.len(), 3 * 24 * 60 * 60 / 7);
+        for (i, tick) in ticks.iter().enumerate() {
+            assert_eq!(*tick - start, period * (i as u32 + 1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_interval_missed_ticks_burst() {
+        let period = Duration::from_secs(10);
+        let start = Instant::now();
+        let ticks = record_ticks(period, MissedTickBehavior::Burst);
+        tokio::task::yield_now().await;
+
+        tokio::time::advance(Duration::from_secs(35)).await;
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        let ticks: Vec<Duration> = ticks.lock().unwrap().iter().map(|t| *t - start).collect();
+        assert_eq!(
+            ticks,
+            vec![
+                Duration::from_secs(35),
+                Duration::from_secs(35),
+                Duration::from_secs(35),
+                Duration::from_secs(40),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_interval_missed_ticks_delay() {
+        let period = Duration::from_secs(10);
+        let start = Instant::now();
+        let ticks = record_ticks(period, MissedTickBehavior::Delay);
+        tokio::task::yield_now().await;
+
+        tokio::time::advance(Duration::from_secs(35)).await;
+        tokio::time::sleep(Duration::from_secs(11)).await;
+
+        let ticks: Vec<Duration> = ticks.lock().unwrap().iter().map(|t| *t - start).collect();
+        assert_eq!(
+            ticks,
+            vec![Duration::from_secs(35), Duration::from_secs(45)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_interval_missed_ticks_skip() {
+        let period = Duration::from_secs(10);
+        let start = Instant::now();
+        let ticks = record_ticks(period, MissedTickBehavior::Skip);
+        tokio::task::yield_now().await;
+
+        tokio::time::advance(Duration::from_secs(35)).await;
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        let ticks: Vec<Duration> = ticks.lock().unwrap().iter().map(|t| *t - start).collect();
+        assert_eq!(
+            ticks,
+            vec![Duration::from_secs(35), Duration::from_secs(40)]
+        );
+    }
+
+    #[test]
+    fn next_occurrence_in_time_zone() {
+        let cron = Cron::new("0 */6 * * *").parse().unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 7, 1, 10, 30, 0).unwrap();
+
+        // Berlin is UTC+2 in summer, so 12:00 local time is 10:00 UTC
+        let next = next_occurrence(&cron, Some(chrono_tz::Europe::Berlin), after);
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 7, 1, 16, 0, 0).unwrap())
+        );
+
+        let next = next_occurrence(&cron, Some(chrono_tz::UTC), after);
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap())
+        );
+    }
+}
@@ -1,48 +1,303 @@
+use log::debug;
+use std::collections::HashMap;
 use std::io;
-use std::process::Output;
+use std::process::{Output, Stdio};
 use std::time::Duration;
-use tokio::process::Command;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
 use tokio::time::Instant;
 
 #[derive(Debug)]
 pub struct ShellCommand {
-    command: String,
-    arguments: String,
+    program: String,
+    arguments: Vec<String>,
+    timeout: Option<Duration>,
+    env: HashMap<String, String>,
+    clear_env: bool,
+    cwd: Option<String>,
+    stdin: Option<String>,
 }
 
 impl ShellCommand {
-    pub fn new(command: &str, arguments: &str) -> Self {
+    /// Runs the command line in the default shell of the OS
+    pub fn new(command: &str) -> Self {
+        Self::with_shell(&default_shell(), command)
+    }
+
+    /// Runs the command line in a shell.
+    /// The first element of `shell` is the program, the others are its arguments.
+    /// The command line is appended as the last argument.
+    pub fn with_shell(shell: &[String], command: &str) -> Self {
+        let mut argv = shell.to_vec();
+        argv.push(command.to_string());
+        Self::from_argv(&argv)
+    }
+
+    /// Runs the program in the first element of `argv` directly without a shell.
+    /// The other elements are passed as arguments without any further processing.
+    pub fn from_argv(argv: &[String]) -> Self {
+        let (program, arguments) = match argv.split_first() {
+            Some((program, arguments)) => (program.clone(), arguments.to_vec()),
+            None => (String::new(), vec![]),
+        };
+
         ShellCommand {
-            command: command.to_string(),
-            arguments: arguments.to_string(),
+            program,
+            arguments,
+            timeout: None,
+            env: HashMap::new(),
+            clear_env: false,
+            cwd: None,
+            stdin: None,
         }
     }
 
+    /// Sets environment variables of the process.
+    /// If `clear_env` is set, the process does not inherit the environment of this program.
+    pub fn with_env(mut self, env: &HashMap<String, String>, clear_env: bool) -> Self {
+        self.env = env.clone();
+        self.clear_env = clear_env;
+        self
+    }
+
+    /// Sets the working directory of the process
+    pub fn with_cwd(mut self, cwd: Option<String>) -> Self {
+        self.cwd = cwd;
+        self
+    }
+
+    /// Writes the text to the standard input of the process, which is closed afterward.
+    /// Without a text the process reads from an empty standard input.
+    pub fn with_stdin(mut self, stdin: Option<String>) -> Self {
+        self.stdin = stdin;
+        self
+    }
+
+    /// Kills the command (and all of its child processes) if it did not exit after the timeout.
+    /// An execution that timed out returns an error of the kind [io::ErrorKind::TimedOut].
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     pub async fn execute(self) -> (io::Result<Output>, Duration) {
         let start = Instant::now();
         let res = self.execute_core().await;
         (res, start.elapsed())
     }
 
-    #[cfg(target_os = "windows")]
     async fn execute_core(self) -> io::Result<Output> {
-        Command::new("cmd")
-            .args([
-                "/C",
-                &*format!("{} {}", self.command, self.arguments).to_string(),
-            ])
-            .output()
-            .await
+        let stdin = match self.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+
+        let mut child = self
+            .build_command()
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        // Kills the remaining processes if this future is dropped before the command exited
+        let process_group = ProcessGroupGuard::new(&child);
+
+        let stdin_pipe = child.stdin.take();
+        let stdin_body = self.stdin;
+        let write_stdin = async move {
+            if let (Some(mut pipe), Some(body)) = (stdin_pipe, stdin_body) {
+                // The process is free to exit without reading everything
+                if let Err(e) = pipe.write_all(body.as_bytes()).await {
+                    debug!("Could not write the whole stdin of the command: {e}");
+                }
+                // The pipe is closed when it is dropped here
+            }
+        };
+
+        // The stdin has to be written while the output is read, otherwise large outputs block the process
+        let run = async move {
+            let (_, output) = tokio::join!(write_stdin, child.wait_with_output());
+            output
+        };
+
+        let output = match self.timeout {
+            None => run.await?,
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(output) => output?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Command did not exit within {timeout:?}"),
+                    ))
+                }
+            },
+        };
+
+        process_group.disarm();
+        Ok(output)
+    }
+
+    fn build_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.arguments);
+
+        if self.clear_env {
+            command.env_clear();
+        }
+        command.envs(&self.env);
+
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        // Start a new process group, so the command and everything it spawned can be killed at once
+        #[cfg(target_os = "linux")]
+        command.process_group(0);
+
+        command
+    }
+}
+
+/// The shell that runs commands if no other shell is configured
+#[cfg(target_os = "windows")]
+fn default_shell() -> Vec<String> {
+    vec!["cmd".to_string(), "/C".to_string()]
+}
+
+/// The shell that runs commands if no other shell is configured
+#[cfg(target_os = "linux")]
+fn default_shell() -> Vec<String> {
+    vec!["sh".to_string(), "-c".to_string()]
+}
+
+/// Kills the whole process group of a child process when it is dropped while still armed.
+///
+/// `kill_on_drop` only kills the direct child (the shell), which would leave processes
+/// like `ssh` that were started by the shell running.
+struct ProcessGroupGuard {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    process_group_id: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    fn new(child: &Child) -> Self {
+        ProcessGroupGuard {
+            process_group_id: child.id(),
+        }
     }
 
+    /// Call this once the child exited to keep the guard from killing the group
+    fn disarm(mut self) {
+        self.process_group_id = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
     #[cfg(target_os = "linux")]
-    async fn execute_core(self) -> io::Result<Output> {
-        Command::new("sh")
-            .args([
-                "-c",
-                &*format!("{} {}", self.command, self.arguments).to_string(),
-            ])
-            .output()
-            .await
+    fn drop(&mut self) {
+        if let Some(id) = self.process_group_id {
+            // A negative pid addresses the whole process group
+            unsafe {
+                libc::kill(-(id as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn drop(&mut self) {}
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    fn stdout(output: &Output) -> &str {
+        std::str::from_utf8(&output.stdout).unwrap().trim_end()
+    }
+
+    #[tokio::test]
+    async fn execute_times_out() {
+        let (res, duration) = ShellCommand::new("sleep 10")
+            .with_timeout(Some(Duration::from_millis(100)))
+            .execute()
+            .await;
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(duration < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn execute_timeout_kills_process_group() {
+        let pid_file = std::env::temp_dir().join(format!("shell-commands-{}", std::process::id()));
+        let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+
+        let (res, _) = ShellCommand::new(&command)
+            .with_timeout(Some(Duration::from_millis(500)))
+            .execute()
+            .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+
+        // The killed process is gone or a zombie until its new parent reaps it
+        for _ in 0..50 {
+            match std::fs::read_to_string(&stat) {
+                Err(_) => return,
+                Ok(stat) if stat.rsplit(") ").next().unwrap().starts_with('Z') => return,
+                Ok(_) => {}
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("The background process of the command is still running");
+    }
+
+    #[tokio::test]
+    async fn execute_argv_without_shell() {
+        let argv = ["echo".to_string(), "$((1 + 1))".to_string()];
+        let (res, _) = ShellCommand::from_argv(&argv).execute().await;
+        assert_eq!(stdout(&res.unwrap()), "$((1 + 1))");
+
+        let (res, _) = ShellCommand::new("echo $((1 + 1))").execute().await;
+        assert_eq!(stdout(&res.unwrap()), "2");
+    }
+
+    #[tokio::test]
+    async fn execute_with_env_cwd_and_stdin() {
+        let shell = ["/bin/sh".to_string(), "-c".to_string()];
+        let env = HashMap::from([("GREETING".to_string(), "hello".to_string())]);
+
+        let (res, _) = ShellCommand::with_shell(&shell, "echo \"$GREETING:$HOME\"")
+            .with_env(&env, false)
+            .execute()
+            .await;
+        assert_eq!(
+            stdout(&res.unwrap()),
+            format!("hello:{}", std::env::var("HOME").unwrap_or_default())
+        );
+
+        let (res, _) = ShellCommand::with_shell(&shell, "echo \"$GREETING:$HOME\"")
+            .with_env(&env, true)
+            .execute()
+            .await;
+        assert_eq!(stdout(&res.unwrap()), "hello:");
+
+        let (res, _) = ShellCommand::new("pwd")
+            .with_cwd(Some("/".to_string()))
+            .execute()
+            .await;
+        assert_eq!(stdout(&res.unwrap()), "/");
+
+        let (res, _) = ShellCommand::new("tr a-z A-Z")
+            .with_stdin(Some("hello".to_string()))
+            .execute()
+            .await;
+        assert_eq!(stdout(&res.unwrap()), "HELLO");
+
+        let (res, _) = ShellCommand::new("cat").execute().await;
+        assert_eq!(stdout(&res.unwrap()), "");
     }
 }
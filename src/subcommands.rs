@@ -0,0 +1,330 @@
+use crate::cli::CliArgs;
+use crate::config::schema::{OutputParser, Schema, TargetCommand};
+use crate::config::{find_cfg_path, read_cfg};
+use crate::json_parser::JsonPaths;
+use crate::prometheus::{
+    encode_metrics, find_matches, parse_output, registered_metric_names, serves_families,
+    target_regex, ParsedResult, TargetMetrics,
+};
+use crate::runner::{handle_target, CommandReport, ConcurrencyLimits};
+use crate::transform::{transform_results, Transform};
+use prometheus_client::registry::Registry;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Reads and validates the config file and prints a report.
+///
+/// Returns the exit code of the process.
+pub fn check_config(cli_args: &CliArgs) -> i32 {
+    match read_config(cli_args) {
+        Ok((config_path, config)) => {
+            println!(
+                "The config file '{config_path}' is valid ({} target(s))",
+                config.targets.len()
+            );
+            for target in &config.targets {
+                println!("  - {} ({} command(s))", target.name, target.commands.len());
+            }
+            0
+        }
+        Err(x) => {
+            eprintln!("{x}");
+            1
+        }
+    }
+}
+
+/// Runs all targets or only the one with the name `target_name` once and prints the
+/// output of the commands, what was parsed from it and the resulting metrics.
+///
+/// Returns the exit code of the process, which is only 0 if every command succeeded.
+pub async fn run_once(cli_args: &CliArgs, target_name: Option<&str>) -> i32 {
+    let config = match read_config(cli_args) {
+        Ok((_, config)) => config,
+        Err(x) => {
+            eprintln!("{x}");
+            return 2;
+        }
+    };
+
+    let targets: Vec<_> = config
+        .targets
+        .iter()
+        .filter(|target| target_name.is_none_or(|name| target.name == name))
+        .collect();
+    if targets.is_empty() {
+        eprintln!(
+            "The config file does not contain a target called '{}'",
+            target_name.unwrap_or_default()
+        );
+        return 2;
+    }
+
+    let limits = ConcurrencyLimits::from_config(&config);
+    let mut registry = Registry::default();
+    let mut exposition_targets = vec![];
+    let mut all_succeeded = true;
+    let reserved_names: HashSet<String> = targets
+        .iter()
+        .flat_map(|target| registered_metric_names(target))
+        .collect();
+
+    for target in targets {
+        let metrics = Arc::new(TargetMetrics::new(target));
+        metrics.register(&mut registry, target);
+        if target.parser == OutputParser::Prometheus {
+            metrics.reserve_names(reserved_names.clone());
+        }
+        if serves_families(target) {
+            exposition_targets.push(metrics.clone());
+        }
+
+        println!("=== Target '{}' ===", target.name);
+        match handle_target(metrics, &limits, target).await {
+            Ok(reports) => {
+                all_succeeded &= reports.len() == target.commands.len()
+                    && reports.iter().all(|report| report.result.is_ok());
+                for report in &reports {
+                    print_command_report(report);
+                }
+            }
+            Err(e) => {
+                all_succeeded = false;
+                println!("Could not run the target: {e}");
+            }
+        }
+        println!();
+    }
+
+    let mut body = String::new();
+    encode_metrics(&mut body, &registry, &exposition_targets).unwrap();
+    println!("=== Metrics ===");
+    print!("{body}");
+
+    match all_succeeded {
+        true => 0,
+        false => 1,
+    }
+}
+
+/// Applies the RegEx of the target with the name `target_name` to the content of the
+/// `input` file (or stdin) the same way it is applied to the output of its commands and
+/// prints the captured groups, the parsed values and the templated labels of every command.
+/// `regex` and `regex_named_group` replace the values of the target if they are set.
+///
+/// Returns the exit code of the process, which is only 0 if a value could be parsed.
+pub fn test_regex(
+    cli_args: &CliArgs,
+    target_name: &str,
+    input: Option<&str>,
+    regex: Option<String>,
+    regex_named_group: Option<String>,
+) -> i32 {
+    let config = match read_config(cli_args) {
+        Ok((_, config)) => config,
+        Err(x) => {
+            eprintln!("{x}");
+            return 2;
+        }
+    };
+
+    let mut target = match config.targets.into_iter().find(|t| t.name == target_name) {
+        Some(x) => x,
+        None => {
+            eprintln!("The config file does not contain a target called '{target_name}'");
+            return 2;
+        }
+    };
+    if target.parser != OutputParser::Regex {
+        eprintln!("The target '{target_name}' does not use the regex parser");
+        return 2;
+    }
+    if let Some(regex) = regex {
+        target.regex = regex;
+    }
+    if let Some(regex_named_group) = regex_named_group {
+        target.regex_named_group = regex_named_group;
+    }
+
+    let mut sample = String::new();
+    let read_result = match input {
+        None | Some("-") => io::stdin().read_to_string(&mut sample),
+        Some(path) => File::open(path).and_then(|mut file| file.read_to_string(&mut sample)),
+    };
+    if let Err(e) = read_result {
+        eprintln!("Could not read the sample output: {e}");
+        return 2;
+    }
+
+    let regex = match target_regex(&target) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Could not build the RegEx: {e}");
+            return 2;
+        }
+    };
+    println!("RegEx: {regex}");
+
+    let matches = find_matches(&target, &regex, &sample);
+    if matches.is_empty() {
+        println!("The RegEx does not match the sample output");
+        return 1;
+    }
+
+    for (number, captures) in matches.iter().enumerate() {
+        println!("match {}:", number + 1);
+        for (index, name) in regex.capture_names().enumerate() {
+            let name = name.map_or(String::new(), |name| format!(" {name}"));
+            match captures.get(index) {
+                Some(group) => println!("  #{index}{name} = {:?}", group.as_str()),
+                None => println!("  #{index}{name} did not participate in the match"),
+            }
+        }
+    }
+
+    // A target without commands still shows the value
+    let default_command = TargetCommand::default();
+    let commands = match target.commands.is_empty() {
+        true => std::slice::from_ref(&default_command),
+        false => &target.commands,
+    };
+
+    let transform = Transform::new(&target);
+    let mut exit_code = 0;
+    for command in commands {
+        println!("--- Command '{command}'");
+        let parsed = parse_output(&target, command, &regex, &JsonPaths::default(), &sample);
+        let parsed = parsed.and_then(|mut results| {
+            transform_results(&target, &transform, &mut results)?;
+            Ok(results)
+        });
+        match parsed {
+            Ok(results) => results.iter().for_each(print_parsed_result),
+            Err(e) => {
+                println!("error: {e}");
+                exit_code = 1;
+            }
+        }
+    }
+    exit_code
+}
+
+/// Reads the config file and formats the problems for the console.
+/// Returns the path of the config file and its content.
+fn read_config(cli_args: &CliArgs) -> Result<(String, Schema), String> {
+    let config_path = match find_cfg_path(cli_args) {
+        Ok(x) => x,
+        Err(x) => return Err(format!("Could not find the config file: {x}")),
+    };
+
+    match read_cfg(&config_path, cli_args) {
+        Ok(config) => Ok((config_path, config)),
+        Err(errors) => Err(format!(
+            "The config file '{config_path}' is not valid\n{errors}"
+        )),
+    }
+}
+
+fn print_command_report(report: &CommandReport) {
+    println!(
+        "--- Command '{}': {} after {:?}",
+        report.command,
+        report.outcome.as_str(),
+        report.duration
+    );
+
+    if let Some(output) = &report.output {
+        println!("{}", output.status);
+        print_stream("stdout", &output.stdout);
+        print_stream("stderr", &output.stderr);
+    }
+
+    match &report.result {
+        Ok(results) => {
+            for parsed in results {
+                println!("captures:");
+                for (group, content) in &parsed.captures {
+                    println!("  {group} = {content:?}");
+                }
+                print_parsed_result(parsed);
+            }
+        }
+        Err(e) => println!("error: {e}"),
+    }
+}
+
+/// Prints the labels and every parsed value of a command
+fn print_parsed_result(parsed: &ParsedResult) {
+    println!("labels: {{{}}}", format_labels(&parsed.labels));
+    println!("values:");
+    for (group, value) in &parsed.values {
+        println!("  {group} = {value}");
+    }
+}
+
+/// Formats labels like they are written in the exposition format
+fn format_labels(labels: &[(String, String)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}={value:?}"))
+        .collect();
+    labels.join(", ")
+}
+
+/// Prints the output of a process indented below its name
+fn print_stream(name: &str, content: &[u8]) {
+    let content = String::from_utf8_lossy(content);
+    if content.trim().is_empty() {
+        println!("{name}: <empty>");
+        return;
+    }
+
+    println!("{name}:");
+    for line in content.lines() {
+        println!("  | {line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the config to a temporary file and checks it
+    fn check_config_text(name: &str, config: &str) -> i32 {
+        let path =
+            std::env::temp_dir().join(format!("check-config-{name}-{}.yaml", std::process::id()));
+        std::fs::write(&path, config).unwrap();
+
+        let exit_code = check_config(&CliArgs {
+            config_file: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        });
+        std::fs::remove_file(&path).unwrap();
+        exit_code
+    }
+
+    #[test]
+    fn check_config_exit_code() {
+        let valid = "\
+host: 127.0.0.1
+port: 9000
+targets:
+  - name: disk
+    commands:
+      - exec: echo 42
+    regex: (?<result>\\d+)
+    regex_named_group: result
+    success_exit_codes: [0]
+    run_every: 10s
+";
+        assert_eq!(check_config_text("valid", valid), 0);
+
+        let invalid = valid.replace("(?<result>", "(?<missing>");
+        assert_eq!(check_config_text("invalid", &invalid), 1);
+
+        assert_eq!(check_config_text("unparsable", "targets: ["), 1);
+    }
+}
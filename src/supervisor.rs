@@ -0,0 +1,477 @@
+use crate::cli::CliArgs;
+use crate::config::read_cfg;
+use crate::config::schema::{OutputParser, Schema, Target};
+use crate::prometheus::{registered_metric_names, serves_families, TargetMetrics};
+use crate::runner::{ConcurrencyLimits, TargetRunner};
+use crate::scheduler::{self, Schedule};
+use crate::AppState;
+use log::{error, info, trace, warn};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// How often the config file is checked for changes
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The names of the metrics of the config reloads
+const RELOAD_METRIC_NAMES: [&str; 2] = [
+    "config_last_reload_successful",
+    "config_last_reload_success_timestamp_seconds",
+];
+
+/// A request to reload the config file
+pub struct ReloadRequest {
+    /// Receives the result of the reload, if the requester is interested in it
+    pub reply: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+pub type ReloadSender = mpsc::UnboundedSender<ReloadRequest>;
+
+/// A target of the current config
+struct RunningTarget {
+    target: Target,
+    metrics: Arc<TargetMetrics>,
+    runner: Arc<TargetRunner>,
+    /// The task that runs the schedule of the target, `None` while it is not started
+    schedule: Option<JoinHandle<()>>,
+}
+
+impl RunningTarget {
+    fn new(target: Target, metrics: Arc<TargetMetrics>, limits: Arc<ConcurrencyLimits>) -> Self {
+        RunningTarget {
+            runner: Arc::new(TargetRunner::new(target.clone(), metrics.clone(), limits)),
+            target,
+            metrics,
+            schedule: None,
+        }
+    }
+
+    /// Replaces the target with a new version, whose runs do not overlap with the run of this one
+    fn restart(
+        &self,
+        target: Target,
+        metrics: Arc<TargetMetrics>,
+        limits: Arc<ConcurrencyLimits>,
+    ) -> Self {
+        RunningTarget {
+            runner: Arc::new(self.runner.restart(target.clone(), metrics.clone(), limits)),
+            target,
+            metrics,
+            schedule: None,
+        }
+    }
+}
+
+impl Drop for RunningTarget {
+    /// Stops the schedule, runs that are already in progress finish normally
+    fn drop(&mut self) {
+        if let Some(schedule) = &self.schedule {
+            schedule.abort();
+        }
+    }
+}
+
+/// Runs the targets of the config and applies changes of the config file to them
+/// without losing the metrics of targets that did not change.
+pub struct Supervisor {
+    config_path: String,
+    cli_args: CliArgs,
+    config: Schema,
+    state: Arc<Mutex<AppState>>,
+    limits: Arc<ConcurrencyLimits>,
+    targets: HashMap<String, RunningTarget>,
+    last_reload_successful: Gauge,
+    last_reload_success_timestamp_seconds: Gauge,
+}
+
+impl Supervisor {
+    /// Creates the metrics of all targets and registers them in the registry of the state.
+    /// The targets are not started before [Supervisor::run] is called.
+    pub fn new(
+        config_path: String,
+        cli_args: CliArgs,
+        config: Schema,
+        state: Arc<Mutex<AppState>>,
+    ) -> Self {
+        let limits = Arc::new(ConcurrencyLimits::from_config(&config));
+        let supervisor = Supervisor {
+            config_path,
+            cli_args,
+            targets: config
+                .targets
+                .iter()
+                .map(|target| {
+                    let metrics = Arc::new(TargetMetrics::new(target));
+                    info!("Created metrics for target: {}", target.name);
+                    (
+                        target.name.clone(),
+                        RunningTarget::new(target.clone(), metrics, limits.clone()),
+                    )
+                })
+                .collect(),
+            limits,
+            config,
+            state,
+            last_reload_successful: Default::default(),
+            last_reload_success_timestamp_seconds: Default::default(),
+        };
+
+        supervisor.record_reload_success();
+        supervisor.register_metrics();
+        supervisor
+    }
+
+    /// Starts all targets and reloads the config whenever a reload is requested.
+    /// Besides the requests from the channel, the config is reloaded if the
+    /// config file changes or (on Linux) the process receives a SIGHUP.
+    pub async fn run(
+        mut self,
+        reload_sender: ReloadSender,
+        mut reload_requests: mpsc::UnboundedReceiver<ReloadRequest>,
+    ) {
+        self.start_targets();
+
+        spawn_config_watcher(self.config_path.clone(), reload_sender.clone());
+        #[cfg(target_os = "linux")]
+        spawn_sighup_listener(reload_sender);
+
+        while let Some(request) = reload_requests.recv().await {
+            let result = self.reload();
+            if let Some(reply) = request.reply {
+                // The requester is allowed to stop waiting for the result
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    /// Reads the config file again and applies it.
+    /// The current config stays active if the new one is not valid.
+    fn reload(&mut self) -> Result<(), String> {
+        info!("Reloading config file: '{}'", self.config_path);
+
+        match read_cfg(&self.config_path, &self.cli_args) {
+            Ok(config) => {
+                self.apply(config);
+                self.record_reload_success();
+                info!("Reloaded the config file");
+                Ok(())
+            }
+            Err(e) => {
+                error!("Could not reload the config file, keeping the current config: {e}");
+                self.last_reload_successful.set(0);
+                Err(e.to_string())
+            }
+        }
+    }
+
+    /// Stops removed targets, starts new ones and restarts changed targets
+    fn apply(&mut self, config: Schema) {
+        if config.host != self.config.host || config.port != self.config.port {
+            warn!("Changes of the host or port are only applied after a restart");
+        }
+
+        let limits_changed = config.max_concurrent_commands != self.config.max_concurrent_commands
+            || config.concurrency_groups != self.config.concurrency_groups;
+        if limits_changed {
+            info!("The concurrency limits changed, restarting all targets");
+            self.limits = Arc::new(ConcurrencyLimits::from_config(&config));
+        }
+
+        let mut previous_targets = std::mem::take(&mut self.targets);
+        for target in &config.targets {
+            let running = match previous_targets.remove(&target.name) {
+                Some(previous) if previous.target == *target && !limits_changed => previous,
+                // The metrics are kept, because they do not depend on the limits
+                Some(previous) if previous.target == *target => previous.restart(
+                    target.clone(),
+                    previous.metrics.clone(),
+                    self.limits.clone(),
+                ),
+                Some(previous) => {
+                    info!("Target '{}' changed, recreating its metrics", target.name);
+                    previous.restart(
+                        target.clone(),
+                        Arc::new(TargetMetrics::new(target)),
+                        self.limits.clone(),
+                    )
+                }
+                None => {
+                    info!("Target '{}' was added", target.name);
+                    RunningTarget::new(
+                        target.clone(),
+                        Arc::new(TargetMetrics::new(target)),
+                        self.limits.clone(),
+                    )
+                }
+            };
+            self.targets.insert(target.name.clone(), running);
+        }
+
+        for name in previous_targets.keys() {
+            info!("Target '{name}' was removed");
+        }
+        // Dropping the previous targets stops their schedules, their runs in progress finish normally
+        drop(previous_targets);
+
+        self.config = config;
+        self.register_metrics();
+        self.start_targets();
+    }
+
+    /// Replaces the registry of the state with one that contains the metrics of the current targets
+    fn register_metrics(&self) {
+        let mut registry = Registry::default();
+
+        registry.register(
+            RELOAD_METRIC_NAMES[0],
+            "Whether the last configuration reload attempt was successful",
+            self.last_reload_successful.clone(),
+        );
+
+        registry.register(
+            RELOAD_METRIC_NAMES[1],
+            "Timestamp of the last successful configuration reload",
+            self.last_reload_success_timestamp_seconds.clone(),
+        );
+
+        let mut reserved_names: HashSet<String> = RELOAD_METRIC_NAMES
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        for target in &self.config.targets {
+            reserved_names.extend(registered_metric_names(target));
+        }
+
+        let mut exposition_targets = vec![];
+        for target in &self.config.targets {
+            let metrics = &self.targets[&target.name].metrics;
+            metrics.register(&mut registry, target);
+            if target.parser == OutputParser::Prometheus {
+                metrics.reserve_names(reserved_names.clone());
+            }
+            if serves_families(target) {
+                exposition_targets.push(metrics.clone());
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.registry = registry;
+        state.exposition_targets = exposition_targets;
+    }
+
+    /// Starts the schedules of all targets that are not running yet
+    fn start_targets(&mut self) {
+        if self.targets.is_empty() {
+            warn!("No targets are configured");
+        }
+
+        for running in self.targets.values_mut() {
+            if running.schedule.is_some() {
+                continue;
+            }
+
+            let schedule = match Schedule::from_target(&running.target) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Invalid schedule for target '{}': {e}", running.target.name);
+                    continue;
+                }
+            };
+            let runner = running.runner.clone();
+
+            trace!("Scheduling target '{}' {schedule}", running.target.name);
+            running.schedule = Some(tokio::spawn(scheduler::run(schedule, move || {
+                runner.trigger()
+            })));
+        }
+    }
+
+    fn record_reload_success(&self) {
+        self.last_reload_successful.set(1);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_reload_success_timestamp_seconds
+            .set(now.as_secs() as i64);
+    }
+}
+
+/// Requests a reload whenever the modification time of the config file changes
+fn spawn_config_watcher(config_path: String, reload_sender: ReloadSender) {
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&config_path);
+        let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let modified = modified_time(&config_path);
+            if modified == last_modified {
+                continue;
+            }
+
+            info!("The config file changed");
+            last_modified = modified;
+            if reload_sender.send(ReloadRequest { reply: None }).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Requests a reload whenever the process receives a SIGHUP
+#[cfg(target_os = "linux")]
+fn spawn_sighup_listener(reload_sender: ReloadSender) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Could not listen for SIGHUP: {e}");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP");
+            if reload_sender.send(ReloadRequest { reply: None }).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::schema::TargetCommand;
+
+    fn target(name: &str, exec: &str) -> Target {
+        Target {
+            name: name.to_string(),
+            commands: vec![TargetCommand {
+                exec: Some(exec.to_string()),
+                ..Default::default()
+            }],
+            regex: r"(?<result>\d+)".to_string(),
+            regex_named_group: "result".to_string(),
+            success_exit_codes: vec![0],
+            run_every: Some("1h".parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn start(targets: Vec<Target>) -> Supervisor {
+        let state = Arc::new(Mutex::new(AppState {
+            registry: Registry::default(),
+            exposition_targets: vec![],
+        }));
+        let config = Schema {
+            targets,
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(String::new(), CliArgs::default(), config, state);
+        supervisor.start_targets();
+        supervisor
+    }
+
+    fn schedule_id(supervisor: &Supervisor, name: &str) -> tokio::task::Id {
+        supervisor.targets[name].schedule.as_ref().unwrap().id()
+    }
+
+    #[tokio::test]
+    async fn apply_changes_of_targets() {
+        let mut supervisor = start(vec![
+            target("unchanged", "echo 1"),
+            target("changed", "echo 1"),
+            target("removed", "echo 1"),
+        ]);
+        let unchanged_metrics = supervisor.targets["unchanged"].metrics.clone();
+        let unchanged_schedule = schedule_id(&supervisor, "unchanged");
+        let changed_metrics = supervisor.targets["changed"].metrics.clone();
+        let changed_schedule = schedule_id(&supervisor, "changed");
+
+        supervisor.apply(Schema {
+            targets: vec![
+                target("unchanged", "echo 1"),
+                target("changed", "echo 2"),
+                target("added", "echo 1"),
+            ],
+            ..Default::default()
+        });
+
+        let mut names: Vec<&String> = supervisor.targets.keys().collect();
+        names.sort();
+        assert_eq!(names, ["added", "changed", "unchanged"]);
+
+        assert!(Arc::ptr_eq(
+            &supervisor.targets["unchanged"].metrics,
+            &unchanged_metrics
+        ));
+        assert_eq!(schedule_id(&supervisor, "unchanged"), unchanged_schedule);
+
+        assert!(!Arc::ptr_eq(
+            &supervisor.targets["changed"].metrics,
+            &changed_metrics
+        ));
+        assert_ne!(schedule_id(&supervisor, "changed"), changed_schedule);
+        assert_eq!(
+            supervisor.targets["changed"].target.commands[0].exec,
+            Some("echo 2".to_string())
+        );
+
+        assert!(supervisor.targets["added"].schedule.is_some());
+    }
+
+    #[tokio::test]
+    async fn apply_changed_limits_restarts_targets() {
+        let mut supervisor = start(vec![target("disk", "echo 1")]);
+        let metrics = supervisor.targets["disk"].metrics.clone();
+        let schedule = schedule_id(&supervisor, "disk");
+        let limits = supervisor.limits.clone();
+
+        supervisor.apply(Schema {
+            targets: vec![target("disk", "echo 1")],
+            max_concurrent_commands: Some(1),
+            ..Default::default()
+        });
+
+        // The metrics are kept, but the schedule runs with the new limits
+        assert!(Arc::ptr_eq(&supervisor.targets["disk"].metrics, &metrics));
+        assert_ne!(schedule_id(&supervisor, "disk"), schedule);
+        assert!(!Arc::ptr_eq(&supervisor.limits, &limits));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn apply_changed_target_does_not_overlap_previous_run() {
+        let mut supervisor = start(vec![target("slow", "sleep 1; echo 1")]);
+        supervisor.targets["slow"].runner.trigger();
+
+        supervisor.apply(Schema {
+            targets: vec![target("slow", "sleep 1; echo 2")],
+            ..Default::default()
+        });
+        supervisor.targets["slow"].runner.trigger();
+
+        // The run of the previous version is still in progress
+        let metrics = &supervisor.targets["slow"].metrics;
+        let skipped = metrics
+            .skipped_runs_total
+            .get_or_create(&vec![("name".to_owned(), "slow".to_owned())])
+            .get();
+        assert_eq!(skipped, 1);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::config::schema::Target;
+use crate::prometheus::TargetMetrics;
+use prometheus_client::registry::Registry;
+use std::process::Output;
+use std::sync::Arc;
+
+/// Creates the metrics of the target and registers them in a new registry
+pub fn registered_metrics(target: &Target) -> (Arc<TargetMetrics>, Registry) {
+    let metrics = Arc::new(TargetMetrics::new(target));
+    let mut registry = Registry::default();
+    metrics.register(&mut registry, target);
+    (metrics, registry)
+}
+
+/// The output of a command that exited successfully and printed `stdout`
+pub fn output(stdout: &str) -> Output {
+    Output {
+        status: Default::default(),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: vec![],
+    }
+}
+
+/// Encodes the metrics of the registry in the text format
+pub fn encoded(registry: &Registry) -> String {
+    let mut body = String::new();
+    prometheus_client::encoding::text::encode(&mut body, registry).unwrap();
+    body
+}
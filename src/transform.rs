@@ -0,0 +1,157 @@
+use crate::config::schema::Target;
+use crate::prometheus::ParsedResult;
+use evalexpr::{
+    build_operator_tree, ContextWithMutableVariables, EvalexprError, HashMapContext, Node, Value,
+};
+
+/// The variable that contains the parsed result in a `transform` expression
+pub const VALUE_VARIABLE: &str = "value";
+
+/// The compiled `transform` expression of a target
+#[derive(Debug, Default)]
+pub struct Transform {
+    /// The operator tree, or the error of an invalid expression
+    tree: Option<Result<Node, String>>,
+}
+
+impl Transform {
+    /// Compiles the `transform` of the target. An invalid expression is reported when it is used.
+    pub fn new(target: &Target) -> Self {
+        Transform {
+            tree: target.transform.as_deref().map(parse_transform),
+        }
+    }
+}
+
+/// Parses a `transform` expression and formats the error for the logs
+pub fn parse_transform(expression: &str) -> Result<Node, String> {
+    match build_operator_tree(expression) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Invalid transform '{expression}': {e}")),
+    }
+}
+
+/// Checks that the expression only reads the given variables, only calls functions that
+/// exist and returns a number, by evaluating it with every variable set to 1
+pub fn check_transform(expression: &str, variables: &[String]) -> Result<(), String> {
+    let tree = parse_transform(expression)?;
+
+    let mut context = HashMapContext::new();
+    for variable in variables {
+        context
+            .set_value(variable.clone(), Value::Float(1.0))
+            .unwrap();
+    }
+    for identifier in tree.iter_read_variable_identifiers() {
+        if !variables.iter().any(|variable| variable == identifier) {
+            return Err(format!(
+                "The transform uses the unknown variable '{identifier}', available are: {}",
+                variables.join(", ")
+            ));
+        }
+    }
+
+    // Named groups that do not contain a number are strings at runtime, which
+    // may fail the operations that expect a string when they are all numbers here
+    match tree.eval_number_with_context(&context) {
+        Ok(_)
+        | Err(EvalexprError::ExpectedString { .. })
+        | Err(EvalexprError::ExpectedNumberOrString { .. })
+        | Err(EvalexprError::TypeError { .. })
+        | Err(EvalexprError::WrongTypeCombination { .. }) => Ok(()),
+        Err(e) => Err(format!("Invalid transform '{expression}': {e}")),
+    }
+}
+
+/// Evaluates the `transform` expression of the target for every result and replaces
+/// the value of the result with its outcome. Results without a result value are kept as they are.
+/// `transform` is the compiled expression of the target.
+pub fn transform_results(
+    target: &Target,
+    transform: &Transform,
+    results: &mut [ParsedResult],
+) -> Result<(), String> {
+    let (Some(expression), Some(tree), Some(result_selector)) =
+        (&target.transform, &transform.tree, target.result_selector())
+    else {
+        return Ok(());
+    };
+    let tree = tree.as_ref().map_err(Clone::clone)?;
+
+    for parsed in results {
+        let Some(index) = parsed
+            .values
+            .iter()
+            .position(|(selector, _)| selector == result_selector)
+        else {
+            continue;
+        };
+
+        // The named groups are numbers if they were parsed as a value or look like one
+        let mut context = HashMapContext::new();
+        for (name, text) in &parsed.captures {
+            let value = match parsed.values.iter().find(|(selector, _)| selector == name) {
+                Some((_, value)) => Value::Float(*value),
+                None => match text.trim().parse::<f64>() {
+                    Ok(number) => Value::Float(number),
+                    Err(_) => Value::String(text.clone()),
+                },
+            };
+            context.set_value(name.clone(), value).unwrap();
+        }
+        context
+            .set_value(
+                VALUE_VARIABLE.to_string(),
+                Value::Float(parsed.values[index].1),
+            )
+            .unwrap();
+
+        match tree.eval_number_with_context(&context) {
+            Ok(value) => parsed.values[index].1 = value,
+            Err(e) => {
+                return Err(format!(
+                    "Could not evaluate the transform '{expression}': {e}"
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_results_with_groups() {
+        let target = Target {
+            regex_named_group: "result".to_string(),
+            transform: Some("min(value * 1024, limit)".to_string()),
+            ..Default::default()
+        };
+        let mut results = vec![ParsedResult {
+            captures: vec![
+                ("result".to_string(), "2".to_string()),
+                ("limit".to_string(), "1000".to_string()),
+            ],
+            labels: vec![],
+            values: vec![("result".to_string(), 2.0)],
+            state: None,
+        }];
+
+        transform_results(&target, &Transform::new(&target), &mut results).unwrap();
+        assert_eq!(results[0].values, vec![("result".to_string(), 1000.0)]);
+    }
+
+    #[test]
+    fn check_transform_unknown_names() {
+        let variables = ["value".to_string()];
+
+        assert!(check_transform("100 - value", &variables).is_ok());
+        assert!(check_transform("value * other", &variables).is_err());
+        assert!(check_transform("minimum(value, 1)", &variables).is_err());
+        assert!(check_transform("value *", &variables).is_err());
+        assert!(check_transform("value > 1", &variables).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::config::schema::{Target, TargetCommand, ValueFormat};
+use regex::Regex;
+use std::env;
+use std::sync::LazyLock;
+
+/// The languages whose locales use a decimal comma, all other languages use a decimal point
+const DECIMAL_COMMA_LANGUAGES: &[&str] = &[
+    "az", "be", "bg", "ca", "cs", "da", "de", "el", "es", "et", "eu", "fi", "fr", "gl", "hr", "hu",
+    "id", "is", "it", "kk", "lt", "lv", "mk", "nb", "nl", "nn", "no", "pl", "pt", "ro", "ru", "sk",
+    "sl", "sq", "sr", "sv", "tr", "uk", "vi",
+];
+
+/// Locales of the languages above that use a decimal point
+const DECIMAL_POINT_LOCALES: &[&str] = &["de_CH", "it_CH"];
+
+/// Characters that are used to group the digits of large numbers
+const GROUPING_CHARACTERS: &[char] = &[' ', '\'', '_', '\u{a0}', '\u{202f}'];
+
+/// Matches durations that consist of numbers with units like `3d 4h`
+static DURATION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:\s*\d+(?:\.\d+)?\s*(?:ns|us|µs|ms|s|sec|m|min|h|d|w))+\s*$").unwrap()
+});
+
+/// Matches a single number with its unit of a duration
+static DURATION_PART_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\d+(?:\.\d+)?)\s*(ns|us|µs|ms|sec|s|min|m|h|d|w)").unwrap());
+
+/// Parses a value from the output of a command of the target.
+/// Words in the `value_map` of the target are mapped to their number, everything
+/// else is parsed with the `value_format` of the target.
+pub fn parse_target_value(
+    target: &Target,
+    command: &TargetCommand,
+    text: &str,
+) -> Result<f64, String> {
+    match target.value_map.get(text.trim()) {
+        Some(value) => Ok(*value),
+        None => parse_value(target.value_format, text, command),
+    }
+}
+
+/// The word of the `value_map` of the target the text was mapped from
+pub fn mapped_state(target: &Target, text: &str) -> Option<String> {
+    target
+        .value_map
+        .contains_key(text.trim())
+        .then(|| text.trim().to_owned())
+}
+
+/// Parses a value from the output of a command and converts it to the base unit of its format.
+/// The locale of the command is only used by the `locale` format.
+pub fn parse_value(
+    format: ValueFormat,
+    text: &str,
+    command: &TargetCommand,
+) -> Result<f64, String> {
+    let text = text.trim();
+    match format {
+        ValueFormat::Plain => parse_number(text),
+        ValueFormat::BytesSi => parse_bytes(text, 1000.0),
+        ValueFormat::BytesIec => parse_bytes(text, 1024.0),
+        ValueFormat::Duration => parse_duration(text),
+        ValueFormat::Percent => {
+            parse_number(text.strip_suffix('%').unwrap_or(text).trim_end()).map(|x| x / 100.0)
+        }
+        ValueFormat::ThousandsSeparated => parse_thousands_separated(text),
+        ValueFormat::Locale => parse_localized(text, uses_decimal_comma(command)),
+    }
+}
+
+fn parse_number(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(format!("'{text}' is not a number")),
+    }
+}
+
+/// Parses a number like `1,234,567.89`, the groups after the first one must have three digits
+fn parse_thousands_separated(text: &str) -> Result<f64, String> {
+    let number: String = text
+        .chars()
+        .map(|c| {
+            if GROUPING_CHARACTERS.contains(&c) {
+                ','
+            } else {
+                c
+            }
+        })
+        .collect();
+    let integer = number.split(['.', 'e', 'E']).next().unwrap_or_default();
+    if integer.split(',').skip(1).any(|group| group.len() != 3) {
+        return Err(format!("'{text}' is not a number"));
+    }
+    parse_number(&number.replace(',', ""))
+}
+
+/// Parses bytes like `1.5G`, `512 kB`, `2MiB` or `1.5Gi`. Units with `iB` or `i` are always binary,
+/// all other units use the `base` of the format.
+fn parse_bytes(text: &str, base: f64) -> Result<f64, String> {
+    let unit_start = text
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .len();
+    let number = parse_number(text[..unit_start].trim_end())?;
+    let unit = text[unit_start..].to_ascii_lowercase();
+
+    let (prefix, base) = match unit.strip_suffix("ib").or_else(|| unit.strip_suffix('i')) {
+        Some(prefix) if !prefix.is_empty() => (prefix, 1024.0),
+        _ => (unit.strip_suffix('b').unwrap_or(&unit), base),
+    };
+    let exponent = match prefix {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        "p" => 5,
+        "e" => 6,
+        _ => return Err(format!("'{text}' has an unknown unit")),
+    };
+
+    Ok(number * f64::powi(base, exponent))
+}
+
+/// Parses a duration like `3d4h`, `1.5s`, `250ms`, `01:02:03` or `3-04:05:06` to seconds.
+/// Numbers without a unit are seconds.
+fn parse_duration(text: &str) -> Result<f64, String> {
+    if let Ok(seconds) = text.parse::<f64>() {
+        return Ok(seconds);
+    }
+
+    // [days-][hours:]minutes:seconds like ps prints the elapsed time
+    if text.contains(':') {
+        let (days, clock) = match text.split_once('-') {
+            Some((days, clock)) => (parse_number(days)?, clock),
+            None => (0.0, text),
+        };
+        let mut seconds = 0.0;
+        for part in clock.split(':') {
+            seconds = seconds * 60.0 + parse_number(part)?;
+        }
+        return Ok(days * 86400.0 + seconds);
+    }
+
+    if !DURATION_REGEX.is_match(text) {
+        return Err(format!("'{text}' is not a duration"));
+    }
+
+    let mut seconds = 0.0;
+    for part in DURATION_PART_REGEX.captures_iter(text) {
+        let unit_seconds = match &part[2] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" | "sec" => 1.0,
+            "m" | "min" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            _ => 604800.0,
+        };
+        seconds += parse_number(&part[1])? * unit_seconds;
+    }
+    Ok(seconds)
+}
+
+/// Parses a number like `1.234.567,89` or `1,234,567.89`, depending on the decimal separator
+fn parse_localized(text: &str, decimal_comma: bool) -> Result<f64, String> {
+    let (decimal_separator, group_separator) = match decimal_comma {
+        true => (',', '.'),
+        false => ('.', ','),
+    };
+
+    let number: String = text
+        .chars()
+        .filter(|c| *c != group_separator && !GROUPING_CHARACTERS.contains(c))
+        .map(|c| if c == decimal_separator { '.' } else { c })
+        .collect();
+    parse_number(&number).map_err(|_| format!("'{text}' is not a number"))
+}
+
+/// Finds the locale of the numbers the command prints like the C library does,
+/// starting with the environment variables of the command
+fn uses_decimal_comma(command: &TargetCommand) -> bool {
+    let locale = ["LC_ALL", "LC_NUMERIC", "LANG"].iter().find_map(|name| {
+        let value = match command.env.get(*name) {
+            Some(value) => Some(value.clone()),
+            None if command.clear_env => None,
+            None => env::var(name).ok(),
+        };
+        value.filter(|value| !value.is_empty())
+    });
+
+    let Some(locale) = locale else {
+        return false;
+    };
+    let language = locale.split(['_', '.', '@']).next().unwrap_or_default();
+
+    DECIMAL_COMMA_LANGUAGES.contains(&language)
+        && !DECIMAL_POINT_LOCALES
+            .iter()
+            .any(|prefix| locale.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(format: ValueFormat, text: &str) -> Result<f64, String> {
+        parse_value(format, text, &TargetCommand::default())
+    }
+
+    #[test]
+    fn parse_bytes() {
+        assert_eq!(parse(ValueFormat::BytesSi, "1.5G"), Ok(1.5e9));
+        assert_eq!(parse(ValueFormat::BytesSi, "512 kB"), Ok(512000.0));
+        assert_eq!(parse(ValueFormat::BytesSi, "2MiB"), Ok(2097152.0));
+        assert_eq!(parse(ValueFormat::BytesIec, "512K"), Ok(524288.0));
+        assert_eq!(parse(ValueFormat::BytesIec, "1.5Gi"), Ok(1610612736.0));
+        assert_eq!(parse(ValueFormat::BytesSi, "2Mi"), Ok(2097152.0));
+        assert!(parse(ValueFormat::BytesIec, "5i").is_err());
+        assert_eq!(parse(ValueFormat::BytesIec, "100"), Ok(100.0));
+        assert!(parse(ValueFormat::BytesIec, "5X").is_err());
+        assert_eq!(parse(ValueFormat::BytesSi, "1.5e3G"), Ok(1.5e12));
+        assert_eq!(parse(ValueFormat::BytesSi, "2e3"), Ok(2000.0));
+    }
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(parse(ValueFormat::Duration, "3d4h"), Ok(273600.0));
+        assert_eq!(parse(ValueFormat::Duration, "1m 30s"), Ok(90.0));
+        assert_eq!(parse(ValueFormat::Duration, "250ms"), Ok(0.25));
+        assert_eq!(parse(ValueFormat::Duration, "01:02:03"), Ok(3723.0));
+        assert_eq!(parse(ValueFormat::Duration, "1-00:00:01"), Ok(86401.0));
+        assert_eq!(parse(ValueFormat::Duration, "42"), Ok(42.0));
+        assert!(parse(ValueFormat::Duration, "3 days").is_err());
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse(ValueFormat::Plain, "1.2e3"), Ok(1200.0));
+        assert_eq!(parse(ValueFormat::Percent, "12%"), Ok(0.12));
+        assert_eq!(
+            parse(ValueFormat::ThousandsSeparated, "1,234,567"),
+            Ok(1234567.0)
+        );
+        assert_eq!(
+            parse(ValueFormat::ThousandsSeparated, "-12 345.5"),
+            Ok(-12345.5)
+        );
+        assert!(parse(ValueFormat::ThousandsSeparated, "1,5").is_err());
+        assert!(parse(ValueFormat::ThousandsSeparated, "1,2345").is_err());
+        assert!(parse(ValueFormat::Plain, "1,5").is_err());
+    }
+
+    #[test]
+    fn parse_locale() {
+        let german = TargetCommand {
+            env: HashMap::from([("LC_ALL".to_string(), "de_DE.UTF-8".to_string())]),
+            ..Default::default()
+        };
+        let english = TargetCommand {
+            env: HashMap::from([("LC_ALL".to_string(), "en_US.UTF-8".to_string())]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            parse_value(ValueFormat::Locale, "1.234.567,5", &german),
+            Ok(1234567.5)
+        );
+        assert_eq!(
+            parse_value(ValueFormat::Locale, "1,234,567.5", &english),
+            Ok(1234567.5)
+        );
+    }
+
+    #[test]
+    fn parse_target_value_mapped() {
+        let target = Target {
+            value_map: HashMap::from([("active".to_string(), 1.0), ("failed".to_string(), -1.0)]),
+            ..Default::default()
+        };
+        let command = TargetCommand::default();
+
+        assert_eq!(parse_target_value(&target, &command, "failed\n"), Ok(-1.0));
+        assert_eq!(parse_target_value(&target, &command, "3"), Ok(3.0));
+        assert!(parse_target_value(&target, &command, "inactive").is_err());
+        assert_eq!(mapped_state(&target, " active"), Some("active".to_string()));
+    }
+}